@@ -1,19 +1,474 @@
 use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
 
 use mdbook::errors::Result;
 use mdbook::{
-    book::Book,
+    book::{Book, Chapter},
     preprocess::{Preprocessor, PreprocessorContext},
     BookItem,
 };
 
-use git2::Repository;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{FixedOffset, LocalResult, TimeZone};
+use git2::{Delta, DiffFindOptions, DiffOptions, Mailmap, Patch, Repository, Signature, Time};
+use handlebars::Handlebars;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::utility::{for_each_chapter_mut, upsert_generated_chapter};
 
 pub struct Authors;
 
+const AUTHORS_CACHE_FILE: &str = "./authors.cache.json";
+/// Bump whenever the layout of `HistoryIndex` changes so old caches are rebuilt.
+const AUTHORS_INDEX_VERSION: u32 = 6;
+const CONTRIBUTORS_MARKER: &str = "<!-- contributors -->";
+const CONTRIBUTORS_TEMPLATE: &str = "contributors";
+/// The data of the template is `heading`, `title` and the list of `contributors` with `name`,
+/// `email` (only with `show-emails`), `email_hash`, `github`, `avatar`, `link`, `commits`,
+/// `lines`, `first_commit_date` and `last_commit_date`.
+const DEFAULT_CONTRIBUTORS_TEMPLATE: &str = "{{heading}}
+
+{{#each contributors}}
+{{#if link}}[{{#if avatar}}{{avatar}} {{/if}}{{name}}]({{link}}){{else}}{{#if avatar}}{{avatar}} {{/if}}{{name}}{{/if}}
+{{/each}}";
+const CONTRIBUTORS_PAGE_TEMPLATE: &str = "contributors_page";
+/// Same data as `DEFAULT_CONTRIBUTORS_TEMPLATE` plus `chapters` for every contributor, the
+/// dates are the first and last contribution to the book.
+const DEFAULT_CONTRIBUTORS_PAGE_TEMPLATE: &str = "# {{title}}
+
+| | Name | Commits | Chapters | First contribution | Last contribution |
+|---|---|---|---|---|---|
+{{#each contributors}}
+| {{avatar}} | {{#if link}}[{{name}}]({{link}}){{else}}{{name}}{{/if}} | {{commits}} | {{chapters}} | {{first_commit_date}} | {{last_commit_date}} |
+{{/each}}";
+const GITHUB_NOREPLY_DOMAIN: &str = "@users.noreply.github.com";
+
+/// How the contributors of a chapter are ranked.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Weight {
+    /// Number of commits which modified the chapter.
+    Commits,
+    /// Number of lines added and removed in the chapter.
+    Lines,
+}
+
+impl Default for Weight {
+    fn default() -> Self {
+        Weight::Commits
+    }
+}
+
+/// Where the avatar of a contributor comes from.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Avatar {
+    /// Gravatar image of the hashed email.
+    Gravatar,
+    /// Inline svg with the initials of the contributor, does not need any external service.
+    Initials,
+    None,
+}
+
+impl Default for Avatar {
+    fn default() -> Self {
+        Avatar::Gravatar
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Config {
+    /// Stores the history index on disk and reuses it as long as `HEAD` does not change.
+    cache: bool,
+    weight: Weight,
+    avatar: Avatar,
+    /// Links every contributor to their email, otherwise only the name is published.
+    show_emails: bool,
+    /// Book level file which merges several identities into one contributor, applied on top
+    /// of the `.mailmap` of the repository.
+    aliases: Option<PathBuf>,
+    /// Which people of a commit are credited for it.
+    count_author: bool,
+    count_committer: bool,
+    count_co_authors: bool,
+    /// Appends the date and the commit of the last change to every chapter.
+    last_updated: bool,
+    /// Text of the footer, `{date}` and `{commit}` are replaced with the last change.
+    last_updated_text: String,
+    /// `strftime` like format of `{date}`.
+    date_format: String,
+    /// Links `{commit}` to the hosting service, `{hash}` is replaced with the full hash.
+    /// For example `https://github.com/OurMachinery/themachinery-books/commit/{hash}`.
+    commit_url: Option<String>,
+    /// Handlebars template of the contributor section, see `DEFAULT_CONTRIBUTORS_TEMPLATE`.
+    template: Option<PathBuf>,
+    heading: String,
+    /// Level of the heading, an `h2` keeps the section below the chapter title in the TOC.
+    heading_level: usize,
+    placement: Placement,
+    /// Only lists the top contributors of a chapter.
+    max_contributors: Option<usize>,
+    /// Source path of a chapter which lists every contributor of the book. The chapter is
+    /// added to the book if it does not exist yet.
+    contributors_page: Option<PathBuf>,
+    contributors_page_title: String,
+    /// Handlebars template of the page, see `DEFAULT_CONTRIBUTORS_PAGE_TEMPLATE`.
+    contributors_page_template: Option<PathBuf>,
+    contributors_page_sort: PageSort,
+    /// Regular expressions, authors whose name or email matches are not credited.
+    exclude_authors: Vec<String>,
+    /// Hashes (or unique prefixes) of commits which are not credited.
+    exclude_commits: Vec<String>,
+    /// Regular expressions, commits whose message matches are not credited, e.g. `^chore:`.
+    exclude_messages: Vec<String>,
+    /// Changes which only touch whitespace are not credited.
+    ignore_whitespace: bool,
+}
+
+/// Order of the contributors on the contributors page.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PageSort {
+    Commits,
+    Lines,
+    Chapters,
+    Name,
+    FirstContribution,
+    LastContribution,
+}
+
+impl Default for PageSort {
+    fn default() -> Self {
+        PageSort::Commits
+    }
+}
+
+/// Where the contributor section is inserted. A `<!-- contributors -->` marker in the chapter
+/// always takes precedence.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Placement {
+    /// At the end of the chapter.
+    End,
+    /// Only at the marker, chapters without one get no contributor section.
+    Marker,
+}
+
+impl Default for Placement {
+    fn default() -> Self {
+        Placement::End
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            cache: false,
+            weight: Weight::default(),
+            avatar: Avatar::default(),
+            show_emails: false,
+            aliases: None,
+            count_author: true,
+            count_committer: false,
+            count_co_authors: true,
+            last_updated: false,
+            last_updated_text: String::from("Last updated on {date} ({commit})"),
+            date_format: String::from("%Y-%m-%d"),
+            commit_url: None,
+            template: None,
+            heading: String::from("Contributors"),
+            heading_level: 2,
+            placement: Placement::default(),
+            max_contributors: None,
+            contributors_page: None,
+            contributors_page_title: String::from("Contributors"),
+            contributors_page_template: None,
+            contributors_page_sort: PageSort::default(),
+            exclude_authors: Vec::new(),
+            exclude_commits: Vec::new(),
+            exclude_messages: Vec::new(),
+            ignore_whitespace: false,
+        }
+    }
+}
+
+/// An entry of the `aliases` file: every email in `aliases` is credited to `name` and `email`.
+#[derive(Deserialize, Debug)]
+struct Identity {
+    name: Option<String>,
+    email: String,
+    #[serde(default)]
+    aliases: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct AliasFile {
+    #[serde(default)]
+    identity: Vec<Identity>,
+}
+
+fn load_aliases(path: &Path) -> AliasFile {
+    match fs::read_to_string(path) {
+        Ok(data) => toml::from_str(&data).unwrap_or_else(|e| {
+            eprintln!("Warning: Cannot parse {:?}: {}", path, e);
+            AliasFile::default()
+        }),
+        Err(_) => {
+            eprintln!("Warning: Cannot read {:?}", path);
+            AliasFile::default()
+        }
+    }
+}
+
+/// Returns the `.mailmap` of the repository extended by the entries of the alias file.
+fn load_mailmap(repo: &Repository, config: &Config) -> std::result::Result<Mailmap, git2::Error> {
+    let mut mailmap = repo.mailmap()?;
+    if let Some(path) = &config.aliases {
+        for identity in load_aliases(path).identity {
+            for alias in &identity.aliases {
+                mailmap.add_entry(identity.name.as_deref(), Some(&identity.email), None, alias)?;
+            }
+        }
+    }
+    Ok(mailmap)
+}
+
+/// Returns the GitHub username of a `[<id>+]<username>@users.noreply.github.com` address.
+fn get_github_username(email: &str) -> Option<&str> {
+    let local = email.strip_suffix(GITHUB_NOREPLY_DOMAIN)?;
+    local.rsplit('+').next()
+}
+
+/// Returns name and email of every `Co-authored-by: Name <email>` trailer of a commit message.
+fn get_co_authors(message: &str) -> Vec<(String, String)> {
+    let re = Regex::new(r"(?im)^co-authored-by:\s*(.*?)\s*<([^>]+)>\s*$").unwrap();
+    re.captures_iter(message)
+        .map(|cap| (cap[1].to_string(), cap[2].to_string()))
+        .collect()
+}
+
+/// The exclude lists of the config with the regular expressions compiled.
+struct Filters {
+    authors: Vec<Regex>,
+    commits: Vec<String>,
+    messages: Vec<Regex>,
+}
+
+fn compile_patterns(patterns: &[String]) -> Vec<Regex> {
+    patterns
+        .iter()
+        .filter_map(|pattern| match Regex::new(pattern) {
+            Ok(re) => Some(re),
+            Err(e) => {
+                eprintln!("Warning: Invalid pattern `{}`: {}", pattern, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Short prefixes would match most of the history, so at least 7 hex digits are required.
+fn get_commit_prefixes(hashes: &[String]) -> Vec<String> {
+    hashes
+        .iter()
+        .filter_map(|hash| {
+            let hash = hash.trim().to_lowercase();
+            if hash.len() >= 7 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                Some(hash)
+            } else {
+                eprintln!(
+                    "Warning: Invalid exclude-commits entry `{}`, expected at least 7 hex digits",
+                    hash
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+impl Filters {
+    fn new(config: &Config) -> Self {
+        Filters {
+            authors: compile_patterns(&config.exclude_authors),
+            commits: get_commit_prefixes(&config.exclude_commits),
+            messages: compile_patterns(&config.exclude_messages),
+        }
+    }
+
+    fn is_excluded_commit(&self, commit: &git2::Commit) -> bool {
+        let id = commit.id().to_string();
+        let message = commit.message().unwrap_or("");
+        self.commits
+            .iter()
+            .any(|hash| id.starts_with(hash.as_str()))
+            || self.messages.iter().any(|re| re.is_match(message))
+    }
+
+    fn is_excluded_author(&self, name: &str, email: &str) -> bool {
+        self.authors
+            .iter()
+            .any(|re| re.is_match(name) || re.is_match(email))
+    }
+}
+
+/// Returns the identity and name of every person who is credited for `commit`.
+fn get_credited(
+    commit: &git2::Commit,
+    mailmap: &Mailmap,
+    config: &Config,
+    filters: &Filters,
+) -> std::result::Result<Vec<(String, String)>, git2::Error> {
+    let mut signatures = Vec::<Signature>::new();
+    if config.count_author {
+        signatures.push(commit.author_with_mailmap(mailmap)?);
+    }
+    if config.count_committer {
+        signatures.push(commit.committer_with_mailmap(mailmap)?);
+    }
+    if config.count_co_authors {
+        for (name, email) in get_co_authors(commit.message().unwrap_or("")) {
+            // libgit2 rejects empty names and emails, one bad trailer must not fail the build.
+            match Signature::new(&name, &email, &Time::new(0, 0)) {
+                Ok(signature) => signatures.push(mailmap.resolve_signature(&signature)?),
+                Err(e) => eprintln!(
+                    "Warning: Skipping the co-author `{} <{}>` of commit {}: {}",
+                    name,
+                    email,
+                    commit.id(),
+                    e.message()
+                ),
+            }
+        }
+    }
+    let mut res = Vec::<(String, String)>::new();
+    for signature in signatures {
+        let name = signature.name().unwrap_or("");
+        if filters.is_excluded_author(name, signature.email().unwrap_or("")) {
+            continue;
+        }
+        let email = get_identity(signature.email().unwrap_or(""));
+        if !res.iter().any(|(e, _)| e == &email) {
+            res.push((email, name.to_string()));
+        }
+    }
+    Ok(res)
+}
+
+/// Key under which the contributions of an email are collected. Both forms of the GitHub
+/// noreply address map to the same identity.
+fn get_identity(email: &str) -> String {
+    match get_github_username(email) {
+        Some(username) => format!("{}{}", username, GITHUB_NOREPLY_DOMAIN).to_lowercase(),
+        None => email.trim().to_lowercase(),
+    }
+}
+
+pub fn load_config(ctx: &PreprocessorContext) -> Config {
+    let mut config: Config = match ctx.config.get("preprocessor.authors") {
+        Some(value) => value.clone().try_into().unwrap_or_else(|e| {
+            eprintln!("Warning: Invalid [preprocessor.authors] config: {}", e);
+            Config::default()
+        }),
+        None => Config::default(),
+    };
+    // chrono panics while formatting with an invalid specifier, so check the format up front.
+    if StrftimeItems::new(&config.date_format).any(|item| item == Item::Error) {
+        eprintln!(
+            "Warning: Invalid date-format `{}`, using the default",
+            config.date_format
+        );
+        config.date_format = Config::default().date_format;
+    }
+    config
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct Entry {
     number: i64,
+    lines: i64,
     name: String,
+    first_commit: Revision,
+    last_commit: Revision,
+}
+
+impl Entry {
+    fn weight(&self, weight: Weight) -> i64 {
+        match weight {
+            Weight::Commits => self.number,
+            Weight::Lines => self.lines,
+        }
+    }
+}
+
+/// A commit which modified a file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Revision {
+    id: String,
+    /// Commit time in seconds since the unix epoch.
+    time: i64,
+    /// Timezone of the commit time in minutes.
+    offset: i32,
+}
+
+impl Revision {
+    fn new(commit: &git2::Commit) -> Self {
+        Revision {
+            id: commit.id().to_string(),
+            time: commit.time().seconds(),
+            offset: commit.time().offset_minutes(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct FileHistory {
+    contributors: HashMap<String, Entry>,
+    last_commit: Option<Revision>,
+}
+
+/// All contributors of every file below `prefix`, collected in a single pass over the history.
+/// A commit only counts for the files its diff modified, renames carry the history along.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct HistoryIndex {
+    head: String,
+    prefix: String,
+    /// Fingerprint of every setting which changes the content of the index.
+    key: String,
+    files: HashMap<String, FileHistory>,
+    /// Contributors of the whole book, every commit is only counted once.
+    contributors: HashMap<String, Entry>,
+}
+
+fn add_contribution(
+    contributors: &mut HashMap<String, Entry>,
+    email: &str,
+    name: &str,
+    lines: i64,
+    revision: &Revision,
+) {
+    match contributors.get_mut(email) {
+        Some(val) => {
+            val.number += 1;
+            val.lines += lines;
+            val.last_commit = revision.clone();
+        }
+        None => {
+            contributors.insert(
+                email.to_string(),
+                Entry {
+                    number: 1,
+                    lines,
+                    name: name.to_string(),
+                    first_commit: revision.clone(),
+                    last_commit: revision.clone(),
+                },
+            );
+        }
+    }
 }
 
 // https://github.com/rust-lang/git2-rs/blob/master/examples/log.rs
@@ -26,72 +481,500 @@ macro_rules! filter_try {
     };
 }
 
-pub fn process(
-    repo: &Result<Repository, git2::Error>,
-    file: &std::path::Path,
-    source: &String,
-) -> Option<String> {
-    let mut res = None;
-    match repo {
-        Ok(repo) => {
-            let mut revwalk = repo.revwalk().unwrap();
-            revwalk
-                .set_sorting(git2::Sort::REVERSE | git2::Sort::TOPOLOGICAL | git2::Sort::TIME)
-                .unwrap();
-            revwalk.push_head().unwrap();
-            let revwalk = revwalk.map(|id| {
-                let id = filter_try!(id);
-                let commit = filter_try!(repo.find_commit(id));
-                Some(Ok(commit))
-            });
-            let mut contributors = HashMap::<String, Entry>::new();
-            for commit in revwalk {
-                let commit = commit.unwrap();
-                let commit = commit.unwrap();
-                let tree = commit.tree().unwrap();
-                let entry = tree.get_path(file);
-                if entry.is_ok() {
-                    let email = commit.author().email().unwrap().to_string();
-                    let name = commit.author().name().unwrap().to_string();
-                    if !contributors.contains_key(&email)
-                        && !email.contains("users.noreply.github.com")
-                    {
-                        contributors.insert(
-                            email,
-                            Entry {
-                                number: 1,
-                                name: name,
-                            },
-                        );
-                    } else if !email.contains("users.noreply.github.com") {
-                        let mut val = contributors.get_mut(&email).unwrap();
-                        val.number += 1;
+fn to_git_path(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+impl HistoryIndex {
+    /// Fingerprint of the settings and files the index depends on besides the history.
+    fn get_key(config: &Config) -> String {
+        let aliases = match &config.aliases {
+            Some(path) => fs::read_to_string(path).unwrap_or_default(),
+            None => String::new(),
+        };
+        format!(
+            "{:x}",
+            md5::compute(format!(
+                "{}\n{:?}\n{}\n{}\n{}\n{:?}\n{:?}\n{:?}\n{}\n{}",
+                AUTHORS_INDEX_VERSION,
+                config.weight,
+                config.count_author,
+                config.count_committer,
+                config.count_co_authors,
+                config.exclude_authors,
+                config.exclude_commits,
+                config.exclude_messages,
+                config.ignore_whitespace,
+                aliases
+            ))
+        )
+    }
+
+    /// Walks the history once. Lines are only counted with `Weight::Lines` or `ignore-whitespace`
+    /// since that requires a full patch for every modified file.
+    pub fn build(
+        repo: &Repository,
+        prefix: &Path,
+        config: &Config,
+    ) -> std::result::Result<Self, git2::Error> {
+        let prefix = to_git_path(prefix);
+        let lines = config.weight == Weight::Lines || config.ignore_whitespace;
+        let mailmap = load_mailmap(repo, config)?;
+        let filters = Filters::new(config);
+        let mut revwalk = repo.revwalk()?;
+        revwalk.set_sorting(git2::Sort::REVERSE | git2::Sort::TOPOLOGICAL | git2::Sort::TIME)?;
+        revwalk.push_head()?;
+        let revwalk = revwalk.map(|id| {
+            let id = filter_try!(id);
+            let commit = filter_try!(repo.find_commit(id));
+            Some(Ok(commit))
+        });
+        let mut files = HashMap::<String, FileHistory>::new();
+        let mut contributors = HashMap::<String, Entry>::new();
+        for commit in revwalk.flatten() {
+            let commit = commit?;
+            // The changes of a merge are already attributed through the merged commits.
+            if commit.parent_count() > 1 {
+                continue;
+            }
+            // Excluded commits are still walked so renames carry the history along.
+            let excluded = filters.is_excluded_commit(&commit);
+            let credited = get_credited(&commit, &mailmap, config, &filters)?;
+            let revision = Revision::new(&commit);
+            let mut touched = false;
+            let mut commit_lines = 0;
+            let tree = commit.tree()?;
+            let parent_tree = match commit.parent_count() {
+                0 => None,
+                _ => Some(commit.parent(0)?.tree()?),
+            };
+            let mut opts = DiffOptions::new();
+            opts.ignore_whitespace(config.ignore_whitespace);
+            if !prefix.is_empty() {
+                opts.pathspec(&prefix);
+            }
+            let mut diff =
+                repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), Some(&mut opts))?;
+            diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+
+            for (idx, delta) in diff.deltas().enumerate() {
+                if delta.status() == Delta::Deleted {
+                    // A file created later at the same path starts with a fresh history.
+                    if let Some(path) = delta.old_file().path() {
+                        files.remove(&to_git_path(path));
+                    }
+                    continue;
+                }
+                let path = match delta.new_file().path() {
+                    Some(path) => to_git_path(path),
+                    None => continue,
+                };
+                if delta.status() == Delta::Renamed {
+                    if let Some(old_path) = delta.old_file().path() {
+                        if let Some(history) = files.remove(&to_git_path(old_path)) {
+                            let file = files.entry(path.clone()).or_default();
+                            if file.last_commit.is_none() {
+                                file.last_commit = history.last_commit;
+                            }
+                            let contributors = &mut file.contributors;
+                            for (email, entry) in history.contributors {
+                                match contributors.get_mut(&email) {
+                                    Some(val) => {
+                                        val.number += entry.number;
+                                        val.lines += entry.lines;
+                                        if entry.first_commit.time < val.first_commit.time {
+                                            val.first_commit = entry.first_commit;
+                                        }
+                                        if entry.last_commit.time > val.last_commit.time {
+                                            val.last_commit = entry.last_commit;
+                                        }
+                                    }
+                                    None => {
+                                        contributors.insert(email, entry);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+                if excluded {
+                    continue;
+                }
+                // A pure rename does not make the author a contributor of the content.
+                if delta.status() == Delta::Renamed
+                    && delta.old_file().id() == delta.new_file().id()
+                {
+                    continue;
+                }
+                let changed_lines = if lines {
+                    match Patch::from_diff(&diff, idx)? {
+                        Some(patch) => {
+                            let (_, additions, deletions) = patch.line_stats()?;
+                            (additions + deletions) as i64
+                        }
+                        None => 0,
                     }
                 } else {
-                    //eprintln!("{:?}", file.as_os_str());
+                    0
+                };
+                if config.ignore_whitespace && changed_lines == 0 {
+                    continue;
                 }
-            }
-            let mut sorted: Vec<_> = contributors.iter().collect();
-            sorted.sort_by(|a, b| b.1.number.cmp(&a.1.number));
-
-            if sorted.len() > 0 {
-                let mut str = format!("{}\n# Contributors\n", source.as_str());
-                for (email, entry) in sorted {
-                    str = format!(
-                        "{}\n[![{}](https://www.gravatar.com/avatar/{}?s=32) {}](mailto:{})",
-                        str.clone(),
-                        &entry.name,
-                        &email,
-                        &email,
-                        &email
+                let file = files.entry(path).or_default();
+                // Commits of excluded authors only, such as a bot reformatting the book, do not
+                // count as an update either.
+                if !credited.is_empty() {
+                    file.last_commit = Some(revision.clone());
+                }
+                for (email, name) in &credited {
+                    add_contribution(
+                        &mut file.contributors,
+                        email,
+                        name,
+                        changed_lines,
+                        &revision,
                     );
                 }
-                res = Some(str);
+                touched = true;
+                commit_lines += changed_lines;
+            }
+            if touched {
+                for (email, name) in &credited {
+                    add_contribution(&mut contributors, email, name, commit_lines, &revision);
+                }
+            }
+        }
+        let head = repo.head()?.peel_to_commit()?.id().to_string();
+        Ok(HistoryIndex {
+            head,
+            prefix,
+            key: HistoryIndex::get_key(config),
+            files,
+            contributors,
+        })
+    }
+
+    /// Returns the cached index from `cache` if it was built for the current `HEAD`,
+    /// otherwise builds a new index and writes it to `cache`.
+    pub fn load_or_build(
+        repo: &Repository,
+        prefix: &Path,
+        config: &Config,
+        cache: Option<&Path>,
+    ) -> std::result::Result<Self, git2::Error> {
+        let head = repo.head()?.peel_to_commit()?.id().to_string();
+        if let Some(cache) = cache {
+            let cached = fs::read_to_string(cache)
+                .ok()
+                .and_then(|data| serde_json::from_str::<HistoryIndex>(&data).ok());
+            if let Some(index) = cached {
+                if index.head == head
+                    && index.prefix == to_git_path(prefix)
+                    && index.key == HistoryIndex::get_key(config)
+                {
+                    return Ok(index);
+                }
+            }
+        }
+        let index = HistoryIndex::build(repo, prefix, config)?;
+        if let Some(cache) = cache {
+            if let Err(e) = fs::write(cache, serde_json::to_string(&index).unwrap()) {
+                eprintln!("Warning: Cannot write {:?}: {}", cache, e);
             }
         }
-        Err(_) => return None,
+        Ok(index)
+    }
+}
+
+pub fn load_template(config: &Config) -> Result<Handlebars<'static>> {
+    let mut handlebars = Handlebars::new();
+    // The section is markdown, the avatars are inline html.
+    handlebars.register_escape_fn(handlebars::no_escape);
+    match &config.template {
+        Some(path) => handlebars.register_template_file(CONTRIBUTORS_TEMPLATE, path)?,
+        None => handlebars
+            .register_template_string(CONTRIBUTORS_TEMPLATE, DEFAULT_CONTRIBUTORS_TEMPLATE)?,
+    }
+    match &config.contributors_page_template {
+        Some(path) => handlebars.register_template_file(CONTRIBUTORS_PAGE_TEMPLATE, path)?,
+        None => handlebars.register_template_string(
+            CONTRIBUTORS_PAGE_TEMPLATE,
+            DEFAULT_CONTRIBUTORS_PAGE_TEMPLATE,
+        )?,
+    }
+    Ok(handlebars)
+}
+
+fn get_contributor_data(config: &Config, email: &str, entry: &Entry) -> serde_json::Value {
+    json!({
+        "name": entry.name,
+        "email": if config.show_emails { Some(email) } else { None },
+        "email_hash": get_email_hash(email),
+        "github": get_github_username(email),
+        "avatar": render_avatar(config, email, &entry.name),
+        "link": get_link(config, email),
+        "commits": entry.number,
+        "lines": entry.lines,
+        "first_commit_date": format_date(
+            entry.first_commit.time,
+            entry.first_commit.offset,
+            &config.date_format
+        ),
+        "last_commit_date": format_date(
+            entry.last_commit.time,
+            entry.last_commit.offset,
+            &config.date_format
+        ),
+    })
+}
+
+/// Renders the page with every contributor of the book. `chapters` are the repository
+/// relative paths of all chapters, only contributions to those count as touched chapters.
+fn render_contributors_page(
+    index: &HistoryIndex,
+    config: &Config,
+    template: &Handlebars,
+    chapters: &[String],
+) -> Option<String> {
+    let mut touched = HashMap::<&str, i64>::new();
+    for chapter in chapters {
+        if let Some(history) = index.files.get(chapter) {
+            for email in history.contributors.keys() {
+                *touched.entry(email.as_str()).or_default() += 1;
+            }
+        }
+    }
+    let get_chapters = |email: &str| touched.get(email).copied().unwrap_or(0);
+
+    let mut sorted: Vec<_> = index.contributors.iter().collect();
+    sorted.sort_by(|a, b| {
+        let order = match config.contributors_page_sort {
+            PageSort::Commits => b.1.number.cmp(&a.1.number),
+            PageSort::Lines => b.1.lines.cmp(&a.1.lines),
+            PageSort::Chapters => get_chapters(b.0).cmp(&get_chapters(a.0)),
+            PageSort::Name => a.1.name.to_lowercase().cmp(&b.1.name.to_lowercase()),
+            PageSort::FirstContribution => a.1.first_commit.time.cmp(&b.1.first_commit.time),
+            PageSort::LastContribution => b.1.last_commit.time.cmp(&a.1.last_commit.time),
+        };
+        order.then_with(|| a.0.cmp(b.0))
+    });
+
+    let contributors: Vec<_> = sorted
+        .iter()
+        .map(|(email, entry)| {
+            let mut data = get_contributor_data(config, email, entry);
+            data["chapters"] = json!(get_chapters(email));
+            data
+        })
+        .collect();
+    let data = json!({
+        "title": config.contributors_page_title,
+        "contributors": contributors,
+    });
+    match template.render(CONTRIBUTORS_PAGE_TEMPLATE, &data) {
+        Ok(page) => Some(page),
+        Err(e) => {
+            eprintln!("Warning: Cannot render the contributors page: {}", e);
+            None
+        }
+    }
+}
+
+/// Renders the contributor section of a chapter, `None` if nobody is credited or the template fails.
+fn render_contributors(
+    config: &Config,
+    template: &Handlebars,
+    history: &FileHistory,
+) -> Option<String> {
+    let mut sorted: Vec<_> = history
+        .contributors
+        .iter()
+        .filter(|(_, entry)| entry.weight(config.weight) > 0)
+        .collect();
+    sorted.sort_by(|a, b| {
+        b.1.weight(config.weight)
+            .cmp(&a.1.weight(config.weight))
+            .then_with(|| a.0.cmp(b.0))
+    });
+    if let Some(max) = config.max_contributors {
+        sorted.truncate(max);
+    }
+
+    if sorted.is_empty() {
+        return None;
+    }
+    let contributors: Vec<_> = sorted
+        .iter()
+        .map(|(email, entry)| get_contributor_data(config, email, entry))
+        .collect();
+    let data = json!({
+        "heading": format!("{} {}", "#".repeat(config.heading_level.max(1)), config.heading),
+        "title": config.heading,
+        "contributors": contributors,
+    });
+    match template.render(CONTRIBUTORS_TEMPLATE, &data) {
+        Ok(section) => Some(section),
+        Err(e) => {
+            eprintln!("Warning: Cannot render the contributors: {}", e);
+            None
+        }
+    }
+}
+
+fn format_date(time: i64, offset: i32, format: &str) -> String {
+    let offset =
+        FixedOffset::east_opt(offset * 60).unwrap_or_else(|| FixedOffset::east_opt(0).unwrap());
+    match offset.timestamp_opt(time, 0) {
+        LocalResult::Single(date) => date.format(format).to_string(),
+        _ => String::new(),
+    }
+}
+
+fn render_last_updated(config: &Config, revision: &Revision) -> String {
+    let short = &revision.id[..revision.id.len().min(7)];
+    let commit = match &config.commit_url {
+        Some(url) => format!("[{}]({})", short, url.replace("{hash}", &revision.id)),
+        None => short.to_string(),
+    };
+    let text = config
+        .last_updated_text
+        .replace(
+            "{date}",
+            &format_date(revision.time, revision.offset, &config.date_format),
+        )
+        .replace("{commit}", &commit);
+    format!("\n\n---\n\n{}\n", text)
+}
+
+pub fn process(
+    index: &HistoryIndex,
+    config: &Config,
+    template: &Handlebars,
+    file: &Path,
+    source: &String,
+) -> Option<String> {
+    let history = index.files.get(&to_git_path(file))?;
+    let contributors = render_contributors(config, template, history);
+    let last_updated = match &history.last_commit {
+        Some(revision) if config.last_updated => Some(render_last_updated(config, revision)),
+        _ => None,
+    };
+    let content = match contributors {
+        Some(section) if source.contains(CONTRIBUTORS_MARKER) => {
+            source.replacen(CONTRIBUTORS_MARKER, &section, 1)
+        }
+        Some(section) if config.placement == Placement::End => {
+            format!("{}\n\n{}", source.as_str(), section)
+        }
+        _ => {
+            if last_updated.is_none() {
+                return None;
+            }
+            source.clone()
+        }
+    };
+    Some(format!("{}{}", content, last_updated.unwrap_or_default()))
+}
+
+/// Gravatar expects the hex encoded md5 hash of the trimmed, lower case email.
+pub fn get_email_hash(email: &str) -> String {
+    format!("{:x}", md5::compute(email.trim().to_lowercase()))
+}
+
+fn get_initials(name: &str) -> String {
+    let words: Vec<&str> = name.split_whitespace().collect();
+    let initials = match (words.first(), words.last()) {
+        (Some(first), Some(last)) if words.len() > 1 => vec![first, last],
+        (Some(first), _) => vec![first],
+        _ => vec![],
     };
-    return res;
+    initials
+        .iter()
+        .filter_map(|word| word.chars().find(|c| c.is_alphanumeric()))
+        .flat_map(|c| c.to_uppercase())
+        .collect()
+}
+
+fn render_initials_avatar(email: &str, name: &str) -> String {
+    let hash = md5::compute(email.trim().to_lowercase());
+    let hue = hash[0] as u32 * 360 / 256;
+    format!(
+        concat!(
+            "<svg width=\"32\" height=\"32\" viewBox=\"0 0 32 32\" role=\"img\" aria-label=\"{}\">",
+            "<circle cx=\"16\" cy=\"16\" r=\"16\" fill=\"hsl({}, 50%, 45%)\"/>",
+            "<text x=\"16\" y=\"21\" font-size=\"13\" text-anchor=\"middle\" fill=\"#fff\">{}</text>",
+            "</svg>"
+        ),
+        get_initials(name),
+        hue,
+        get_initials(name)
+    )
+}
+
+fn render_avatar(config: &Config, email: &str, name: &str) -> String {
+    match config.avatar {
+        Avatar::Gravatar if get_github_username(email).is_some() => format!(
+            "![{}](https://github.com/{}.png?size=32)",
+            name,
+            get_github_username(email).unwrap()
+        ),
+        Avatar::Gravatar => format!(
+            "![{}](https://www.gravatar.com/avatar/{}?s=32&d=identicon)",
+            name,
+            get_email_hash(email)
+        ),
+        Avatar::Initials => render_initials_avatar(email, name),
+        Avatar::None => String::new(),
+    }
+}
+
+/// Links a contributor to their email or GitHub profile, only with `show-emails`.
+fn get_link(config: &Config, email: &str) -> Option<String> {
+    if !config.show_emails {
+        return None;
+    }
+    match get_github_username(email) {
+        Some(username) => Some(format!("https://github.com/{}", username)),
+        None => Some(format!("mailto:{}", email)),
+    }
+}
+
+/// Discovers the repository which contains the book and returns it together with the
+/// repository relative path of the book's source folder.
+fn open_repository(ctx: &PreprocessorContext) -> Option<(Repository, PathBuf)> {
+    let repo = match Repository::discover(&ctx.root) {
+        Ok(repo) => repo,
+        Err(e) => {
+            eprintln!(
+                "Warning: The book at {:?} is not part of a git repository: {}",
+                ctx.root,
+                e.message()
+            );
+            return None;
+        }
+    };
+    if repo.is_shallow() {
+        eprintln!(
+            "Warning: The repository is a shallow clone, the contributors are incomplete. Run `git fetch --unshallow` to fetch the full history."
+        );
+    }
+    let workdir = match repo.workdir() {
+        Some(workdir) => fs::canonicalize(workdir).ok()?,
+        None => {
+            eprintln!("Warning: The repository of the book is bare");
+            return None;
+        }
+    };
+    let src = ctx.root.join(&ctx.config.book.src);
+    let src = match fs::canonicalize(&src) {
+        Ok(src) => src,
+        Err(_) => {
+            eprintln!("Warning: Path: {:?} does not exist!", src);
+            return None;
+        }
+    };
+    let prefix = src.strip_prefix(&workdir).ok()?.to_path_buf();
+    Some((repo, prefix))
 }
 
 impl Preprocessor for Authors {
@@ -99,50 +982,266 @@ impl Preprocessor for Authors {
         "authors"
     }
 
-    fn run(&self, _: &PreprocessorContext, mut book: Book) -> Result<Book> {
-        let res = None;
-        book.for_each_mut(|item: &mut BookItem| {
-            if let Some(Err(_)) = res {
-                return;
-            }
+    fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
+        let config = load_config(ctx);
+        let (repo, src) = match open_repository(ctx) {
+            Some(res) => res,
+            None => return Ok(book),
+        };
+        let cache = if config.cache {
+            Some(ctx.root.join(AUTHORS_CACHE_FILE))
+        } else {
+            None
+        };
+        let index = HistoryIndex::load_or_build(&repo, &src, &config, cache.as_deref())?;
+        let template = load_template(&config)?;
 
-            if let BookItem::Chapter(ref mut chapter) = *item {
+        for_each_chapter_mut(&mut book.sections, &|chapter: &mut Chapter| {
+            if let Some(path) = chapter.source_path.as_ref() {
+                // The contributors page gets the list of the whole book instead.
+                if config.contributors_page.as_ref() == Some(path) {
+                    return Ok(());
+                }
                 let content = chapter.content.to_string();
-                let path = chapter.source_path.as_ref();
-                let cwd = std::env::current_dir().unwrap();
-                let repo = Repository::open(&cwd);
-
-                let file =
-                    std::path::Path::new(std::env::current_dir().unwrap().file_name().unwrap())
-                        .join("src")
-                        .join(path.unwrap());
-                let processed_data = process(&repo, &file, &content);
-                if processed_data.is_none() {
-                    let file_path = std::path::Path::new(
-                        cwd.parent().unwrap().file_name().unwrap().to_str().unwrap(),
-                    )
-                    .join("src")
-                    .join(path.unwrap());
-                    let repo = Repository::open(cwd.parent().unwrap());
-                    let processed_data = process(&repo, &file_path, &content);
-                    if processed_data.is_some() {
-                        chapter.content = processed_data.unwrap();
-                    } else {
-                        let processed_data = process(&repo, &file_path, &content);
-                        if processed_data.is_some() {
-                            chapter.content = processed_data.unwrap();
-                        }
+                if let Some(processed_data) =
+                    process(&index, &config, &template, &src.join(path), &content)
+                {
+                    chapter.content = processed_data;
+                }
+            }
+            Ok(())
+        })?;
+
+        if let Some(page_path) = &config.contributors_page {
+            let mut chapters = Vec::<String>::new();
+            for item in book.iter() {
+                if let BookItem::Chapter(ref chapter) = *item {
+                    if let Some(path) = chapter.source_path.as_ref() {
+                        chapters.push(to_git_path(&src.join(path)));
                     }
-                } else {
-                    chapter.content = processed_data.unwrap();
                 }
             }
-        });
+            if let Some(page) = render_contributors_page(&index, &config, &template, &chapters) {
+                upsert_generated_chapter(
+                    &mut book,
+                    page_path,
+                    &config.contributors_page_title,
+                    CONTRIBUTORS_MARKER,
+                    page,
+                );
+            }
+        }
 
-        res.unwrap_or(Ok(())).map(|_| book)
+        Ok(book)
     }
 
     fn supports_renderer(&self, _renderer: &str) -> bool {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identities() {
+        let table = [
+            ("Ada@Example.com ", "ada@example.com", None),
+            (
+                "12345+Octo-Cat@users.noreply.github.com",
+                "octo-cat@users.noreply.github.com",
+                Some("Octo-Cat"),
+            ),
+            (
+                "octo-cat@users.noreply.github.com",
+                "octo-cat@users.noreply.github.com",
+                Some("octo-cat"),
+            ),
+        ];
+        for (email, identity, username) in table.iter() {
+            assert_eq!(get_identity(email), *identity, "{}", email);
+            assert_eq!(get_github_username(email), *username, "{}", email);
+        }
+    }
+
+    #[test]
+    fn initials() {
+        let table = [
+            ("Ada Lovelace", "AL"),
+            ("plato", "P"),
+            ("Jean-Luc de Picard", "JP"),
+            ("  (anonymous)  ", "A"),
+            ("", ""),
+        ];
+        for (name, initials) in table.iter() {
+            assert_eq!(get_initials(name), *initials, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn commit_prefixes() {
+        let hashes: Vec<String> = [
+            "",
+            "a",
+            "abc123",
+            "ABCDEF1",
+            " 0123456789abcdef ",
+            "xyz1234",
+        ]
+        .iter()
+        .map(|hash| hash.to_string())
+        .collect();
+        assert_eq!(
+            get_commit_prefixes(&hashes),
+            vec!["abcdef1", "0123456789abcdef"]
+        );
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(format_date(0, 0, "%Y-%m-%d"), "1970-01-01");
+        assert_eq!(format_date(0, 60, "%Y-%m-%d %H:%M"), "1970-01-01 01:00");
+        assert_eq!(format_date(0, -60, "%d.%m.%Y"), "31.12.1969");
+        // An offset out of range falls back to UTC.
+        assert_eq!(format_date(0, 100_000, "%H"), "00");
+    }
+
+    #[test]
+    fn co_authors() {
+        let message = "Fix the docs\n\n\
+                       Co-authored-by: Ada Lovelace <ada@example.com>\n\
+                       co-AUTHORED-by:   Bob  Smith   <bob@example.com>  \n\
+                       Co-authored-by: <bot@example.com>\n\
+                       Signed-off-by: Cy <cy@example.com>\n\
+                       Mentions Co-authored-by: Dee <dee@example.com> inline\n";
+        assert_eq!(
+            get_co_authors(message),
+            vec![
+                (
+                    String::from("Ada Lovelace"),
+                    String::from("ada@example.com")
+                ),
+                (String::from("Bob  Smith"), String::from("bob@example.com")),
+                (String::new(), String::from("bot@example.com")),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_co_authors_are_skipped() {
+        let mut repo = TestRepo::new("co-authors");
+        repo.commit_message(
+            "Ada",
+            "Add a chapter\n\nCo-authored-by: <bot@example.com>\nCo-authored-by: Bob <bob@example.com>\n",
+            &[("a.md", Some("# Chapter\n"))],
+        );
+        assert_eq!(
+            repo.get_contributors("a.md"),
+            vec!["ada@example.com", "bob@example.com"]
+        );
+    }
+
+    /// A repository in the temp dir which is removed again on drop.
+    struct TestRepo {
+        path: PathBuf,
+        repo: Repository,
+        time: i64,
+    }
+
+    impl TestRepo {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "tmbook-authors-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&path);
+            let repo = Repository::init(&path).unwrap();
+            TestRepo {
+                path,
+                repo,
+                time: 1_600_000_000,
+            }
+        }
+
+        fn commit(&mut self, author: &str, files: &[(&str, Option<&str>)]) {
+            self.commit_message(author, "Change", files);
+        }
+
+        /// Writes `files`, removes the ones with `None` content and commits everything.
+        fn commit_message(&mut self, author: &str, message: &str, files: &[(&str, Option<&str>)]) {
+            for (name, content) in files {
+                let path = self.path.join(name);
+                match content {
+                    Some(content) => fs::write(path, content).unwrap(),
+                    None => fs::remove_file(path).unwrap(),
+                }
+            }
+            let mut index = self.repo.index().unwrap();
+            index
+                .add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)
+                .unwrap();
+            index.update_all(["*"].iter(), None).unwrap();
+            index.write().unwrap();
+            let tree = self.repo.find_tree(index.write_tree().unwrap()).unwrap();
+            self.time += 60;
+            let email = format!("{}@example.com", author.to_lowercase());
+            let signature = Signature::new(author, &email, &Time::new(self.time, 0)).unwrap();
+            let parent = self
+                .repo
+                .head()
+                .ok()
+                .and_then(|head| head.peel_to_commit().ok());
+            let parents: Vec<&git2::Commit> = parent.iter().collect();
+            self.repo
+                .commit(
+                    Some("HEAD"),
+                    &signature,
+                    &signature,
+                    message,
+                    &tree,
+                    &parents,
+                )
+                .unwrap();
+        }
+
+        fn get_contributors(&self, file: &str) -> Vec<String> {
+            let index = HistoryIndex::build(&self.repo, Path::new(""), &Config::default()).unwrap();
+            let mut res: Vec<String> = match index.files.get(file) {
+                Some(history) => history.contributors.keys().cloned().collect(),
+                None => Vec::new(),
+            };
+            res.sort();
+            res
+        }
+    }
+
+    impl Drop for TestRepo {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    #[test]
+    fn history_follows_renames_and_deletes() {
+        let content = "# Chapter\n\nA chapter with enough text to be detected as renamed.\n";
+        let mut repo = TestRepo::new("history");
+        repo.commit("Ada", &[("a.md", Some(content))]);
+        // A pure rename does not credit the author.
+        repo.commit("Bob", &[("a.md", None), ("b.md", Some(content))]);
+        assert_eq!(repo.get_contributors("a.md"), Vec::<String>::new());
+        assert_eq!(repo.get_contributors("b.md"), vec!["ada@example.com"]);
+
+        repo.commit("Cy", &[("b.md", Some(&format!("{}More.\n", content)))]);
+        assert_eq!(
+            repo.get_contributors("b.md"),
+            vec!["ada@example.com", "cy@example.com"]
+        );
+
+        // A new file at the path of a deleted one starts with a fresh history.
+        repo.commit("Dee", &[("b.md", None), ("c.md", Some("other\n"))]);
+        repo.commit("Eve", &[("b.md", Some("# New chapter\n"))]);
+        assert_eq!(repo.get_contributors("b.md"), vec!["eve@example.com"]);
+    }
+}
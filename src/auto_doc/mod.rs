@@ -1,32 +1,494 @@
 extern crate mdbook;
 extern crate serde_json;
 
-use mdbook::book::{Book, BookItem};
-use mdbook::errors::Result;
+use mdbook::book::{Book, BookItem, Chapter};
+use mdbook::errors::{Error, Result};
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
-use pulldown_cmark::{CowStr, Event, LinkType, Options, Parser, Tag};
+use pulldown_cmark::{CodeBlockKind, CowStr, Event, LinkType, Options, Parser, Tag};
 use pulldown_cmark_to_cmark::cmark;
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Mutex;
 
 use serde::{Deserialize, Serialize};
 use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::utility::{
+    for_each_chapter_mut, get_chapter_name, upsert_generated_chapter, warn_chapter,
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Term {
-    term: String,
-    path: String,
-    file: String,
-    name: String,
-    id: serde_json::Number,
+    pub term: String,
+    pub path: String,
+    pub file: String,
+    pub name: String,
+    pub id: serde_json::Number,
+    /// The declaration and the first sentence of its doc comment, shown on hover.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+}
+
+/// The content of a terms.json, older files are a plain list of terms.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct TermsFile {
+    /// The SDK version the terms were generated from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// The commit of the headers, if they are part of a git repository.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    pub terms: Vec<Term>,
+}
+
+impl TermsFile {
+    pub fn parse(data: &str) -> serde_json::Result<TermsFile> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Data {
+            File(TermsFile),
+            List(Vec<Term>),
+        }
+        Ok(match serde_json::from_str(data)? {
+            Data::File(file) => file,
+            Data::List(terms) => TermsFile {
+                terms,
+                ..TermsFile::default()
+            },
+        })
+    }
 }
 
 pub struct AutoDoc;
 
-pub fn load_config() -> HashMap<String, Term> {
-    let path = "./terms.json";
-    let data = fs::read_to_string(path).expect("Unable to read file");
-    let terms: Vec<Term> = serde_json::from_str(&data).expect("Unable to parse");
+/// The name of the term set used when `[preprocessor.auto_doc.terms]` is not configured.
+const DEFAULT_TERM_SET: &str = "default";
+const API_INDEX_MARKER: &str = "<!-- api-index -->";
+/// `[[tm_foo_api]]` or `[[tm_foo_api|custom text]]`.
+const EXPLICIT_LINK: &str = r"\[\[([^\[\]|]+)(?:\|([^\[\]]+))?\]\]";
+/// Everything between `<!-- auto_doc: off -->` and `<!-- auto_doc: on -->` is left as is.
+const TOGGLE: &str = r"<!--\s*auto_doc:\s*(off|on)\s*-->";
+/// The front matter keys auto_doc understands.
+const FRONT_MATTER_KEYS: &[&str] = &["terms"];
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct TermSetConfig {
+    /// The terms.json of the set, relative to the book root.
+    file: PathBuf,
+    /// Prepended to `{path}.html#{name}` of every term of the set.
+    base_url: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Config {
+    /// Named term sets, e.g. one per engine version or SDK. Without any, `./terms.json` is
+    /// linked to `{{docs}}` which replace_path fills in.
+    terms: BTreeMap<String, TermSetConfig>,
+    /// The set of chapters which select none, the first set if not given.
+    default_terms: Option<String>,
+    /// Selects the set of all chapters below a path, the longest matching path wins. A chapter
+    /// can also select its set with `terms: <name>` in its front matter, which is removed from
+    /// the output.
+    chapters: BTreeMap<PathBuf, String>,
+    /// Reports every inline code span matching `unknown-pattern` which is not a known term or
+    /// only links to its parent, such as a misspelled `tm_foo_api.bar()`.
+    verbose: bool,
+    /// Code which looks like a term, its `!` escape is removed even if the term is unknown.
+    unknown_pattern: String,
+    /// How many "did you mean" suggestions are listed per unknown term.
+    suggestions: usize,
+    /// Links the terms inside fenced code blocks of these languages. Only done for the html
+    /// renderer since it replaces the block with a pre-highlighted `<pre><code>`.
+    code_blocks: bool,
+    code_block_languages: Vec<String>,
+    /// Adds the signature and summary of a term as title to its links for the html renderer.
+    titles: bool,
+    /// Source path of a chapter which lists every linked term with the chapters mentioning it.
+    /// The chapter is added to the book if it does not exist yet.
+    api_index: Option<PathBuf>,
+    api_index_title: String,
+    /// Also lists the terms no chapter mentions.
+    api_index_uncovered: bool,
+    /// Leaves inline code inside links and images alone, a link cannot contain another one.
+    skip_links: bool,
+    skip_images: bool,
+    /// Leaves inline code inside headings alone, so their anchors and TOC entries stay stable.
+    skip_headings: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            terms: BTreeMap::new(),
+            default_terms: None,
+            chapters: BTreeMap::new(),
+            verbose: false,
+            unknown_pattern: String::from("^tm_|^TM_"),
+            suggestions: 3,
+            code_blocks: false,
+            code_block_languages: vec![String::from("c"), String::from("cpp"), String::from("h")],
+            titles: true,
+            api_index: None,
+            api_index_title: String::from("API Index"),
+            api_index_uncovered: false,
+            skip_links: true,
+            skip_images: true,
+            skip_headings: true,
+        }
+    }
+}
+
+pub fn load_config(ctx: &PreprocessorContext) -> Config {
+    match ctx.config.get("preprocessor.auto_doc") {
+        Some(value) => value.clone().try_into().unwrap_or_else(|e| {
+            eprintln!("Warning: Invalid [preprocessor.auto_doc] config: {}", e);
+            Config::default()
+        }),
+        None => Config::default(),
+    }
+}
+
+pub struct TermSet {
+    name: String,
+    lookup: HashMap<String, Term>,
+    base_url: String,
+}
+
+impl TermSet {
+    fn get_link(&self, term: &Term) -> String {
+        format!("{}{}.html#{}", self.base_url, term.path, term.name)
+    }
+}
+
+pub fn load_term_sets(
+    ctx: &PreprocessorContext,
+    config: &Config,
+) -> Result<BTreeMap<String, TermSet>> {
+    let mut sets = BTreeMap::<String, TermSet>::new();
+    if config.terms.is_empty() {
+        sets.insert(
+            String::from(DEFAULT_TERM_SET),
+            TermSet {
+                name: String::from(DEFAULT_TERM_SET),
+                lookup: load_terms(&ctx.root.join("./terms.json"))?,
+                base_url: String::from("{{docs}}"),
+            },
+        );
+    }
+    for (name, set) in &config.terms {
+        sets.insert(
+            name.clone(),
+            TermSet {
+                name: name.clone(),
+                lookup: load_terms(&ctx.root.join(&set.file))?,
+                base_url: set.base_url.clone(),
+            },
+        );
+    }
+    Ok(sets)
+}
+
+/// Splits the front matter off a chapter, e.g. `---\nterms: 2021.11\n---\n`. Returns its
+/// `key: value` pairs, the remaining content and the number of lines the front matter took.
+/// The block is removed from the output, so it only counts as front matter if it sets one of
+/// `FRONT_MATTER_KEYS`: `---\nNote: read this first\n---` is a thematic break and a heading.
+fn split_front_matter(content: &str) -> (HashMap<String, String>, &str, usize) {
+    let re = Regex::new(r"(?s)\A---\r?\n(.*?)\r?\n---[ \t]*(\r?\n|\z)").unwrap();
+    let entry = Regex::new(r"^\s*([A-Za-z0-9_-]+)\s*:\s*(.*?)\s*$").unwrap();
+    let mut res = HashMap::<String, String>::new();
+    if let Some(cap) = re.captures(content) {
+        // Otherwise it is a thematic break followed by a heading.
+        for line in cap[1].lines().filter(|line| !line.trim().is_empty()) {
+            match entry.captures(line) {
+                Some(entry) => res.insert(entry[1].to_string(), entry[2].to_string()),
+                None => return (HashMap::new(), content, 0),
+            };
+        }
+        if !FRONT_MATTER_KEYS.iter().any(|key| res.contains_key(*key)) {
+            return (HashMap::new(), content, 0);
+        }
+        let end = cap.get(0).unwrap().end();
+        return (res, &content[end..], content[..end].matches('\n').count());
+    }
+    (res, content, 0)
+}
+
+/// Everything `process` needs besides the chapter, prepared once per book.
+pub struct Linker<'a> {
+    sets: &'a BTreeMap<String, TermSet>,
+    default_set: String,
+    chapters: &'a BTreeMap<PathBuf, String>,
+    reporter: Option<Reporter>,
+    /// The compiled `unknown-pattern`, `None` if it is invalid.
+    unknown_pattern: Option<Regex>,
+    /// Languages of the code blocks which are linked, empty when disabled.
+    code_block_languages: &'a [String],
+    titles: bool,
+    skip_links: bool,
+    skip_images: bool,
+    skip_headings: bool,
+    /// The chapters mentioning each `(set, term)`, only collected for the API index.
+    usage: Option<Mutex<BTreeMap<(String, String), Vec<PathBuf>>>>,
+}
+
+impl<'a> Linker<'a> {
+    pub fn new(sets: &'a BTreeMap<String, TermSet>, config: &'a Config, renderer: &str) -> Self {
+        let html = renderer == "html";
+        let first = sets.keys().next().cloned().unwrap_or_default();
+        let default_set = match &config.default_terms {
+            Some(name) if sets.contains_key(name) => name.clone(),
+            Some(name) => {
+                eprintln!("Warning: Unknown default-terms `{}`", name);
+                first
+            }
+            None => first,
+        };
+        let unknown_pattern = match Regex::new(&config.unknown_pattern) {
+            Ok(pattern) => Some(pattern),
+            Err(e) => {
+                eprintln!(
+                    "Warning: Invalid unknown-pattern `{}`: {}",
+                    config.unknown_pattern, e
+                );
+                None
+            }
+        };
+        Linker {
+            sets,
+            default_set,
+            chapters: &config.chapters,
+            reporter: Reporter::new(config, unknown_pattern.as_ref()),
+            unknown_pattern,
+            code_block_languages: if config.code_blocks && html {
+                &config.code_block_languages
+            } else {
+                &[]
+            },
+            titles: config.titles && html,
+            skip_links: config.skip_links,
+            skip_images: config.skip_images,
+            skip_headings: config.skip_headings,
+            usage: config
+                .api_index
+                .as_ref()
+                .map(|_| Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    fn add_usage(&self, set: &TermSet, terms: BTreeSet<String>, chapter: &Chapter) {
+        if let (Some(usage), Some(path)) = (&self.usage, &chapter.path) {
+            let mut usage = usage.lock().unwrap();
+            for term in terms {
+                usage
+                    .entry((set.name.clone(), term))
+                    .or_default()
+                    .push(path.clone());
+            }
+        }
+    }
+
+    /// Lists the used terms of every set grouped by header, each with the chapters using it.
+    /// `chapters` are the paths and names of all chapters in book order.
+    fn render_api_index(
+        &self,
+        config: &Config,
+        page_path: &Path,
+        chapters: &[(PathBuf, String)],
+    ) -> Option<String> {
+        let usage = self.usage.as_ref()?.lock().unwrap();
+        // Chapter links are relative to the index page.
+        let depth = page_path.parent().map_or(0, |p| p.components().count());
+        let root = "../".repeat(depth);
+        let level = if self.sets.len() > 1 { "###" } else { "##" };
+
+        let mut page = format!("# {}\n", config.api_index_title);
+        for (name, set) in self.sets {
+            let mut files = BTreeMap::<&str, Vec<String>>::new();
+            let mut uncovered = BTreeMap::<&str, Vec<String>>::new();
+            let mut terms: Vec<&Term> = set.lookup.values().collect();
+            terms.sort_by(|a, b| a.term.cmp(&b.term));
+            for term in terms {
+                let entry = format!("- [`{}`]({})", term.term, set.get_link(term));
+                match usage.get(&(name.clone(), term.term.clone())) {
+                    Some(paths) => {
+                        let links: Vec<String> = chapters
+                            .iter()
+                            .filter(|(path, _)| paths.contains(path))
+                            .map(|(path, title)| {
+                                let path = path.to_string_lossy().replace('\\', "/");
+                                format!("[{}]({}{})", title, root, path)
+                            })
+                            .collect();
+                        let entry = format!("{}: {}", entry, links.join(", "));
+                        files.entry(&term.path).or_default().push(entry);
+                    }
+                    None if config.api_index_uncovered => {
+                        uncovered.entry(&term.path).or_default().push(entry);
+                    }
+                    None => {}
+                }
+            }
+            if files.is_empty() && uncovered.is_empty() {
+                continue;
+            }
+            if self.sets.len() > 1 {
+                page.push_str(&format!("\n## {}\n", name));
+            }
+            for (file, entries) in files {
+                page.push_str(&format!("\n{} {}\n\n{}\n", level, file, entries.join("\n")));
+            }
+            if !uncovered.is_empty() {
+                page.push_str(&format!("\n{} Not covered\n", level));
+                for (file, entries) in uncovered {
+                    page.push_str(&format!("\n**{}**\n\n{}\n", file, entries.join("\n")));
+                }
+            }
+        }
+        Some(page)
+    }
+
+    /// The set selected by the front matter of the chapter, its path or the default.
+    fn get_term_set(&self, chapter: &Chapter, name: Option<&String>) -> &TermSet {
+        let name = name.cloned().or_else(|| {
+            let path = chapter.path.as_ref()?;
+            self.chapters
+                .iter()
+                .filter(|(prefix, _)| path.starts_with(prefix))
+                .max_by_key(|(prefix, _)| prefix.components().count())
+                .map(|(_, name)| name.clone())
+        });
+        if let Some(name) = name {
+            match self.sets.get(&name) {
+                Some(set) => return set,
+                None => eprintln!(
+                    "Warning: Unknown term set `{}` in {}",
+                    name,
+                    get_chapter_name(chapter)
+                ),
+            }
+        }
+        &self.sets[&self.default_set]
+    }
+
+    /// Whether the content of `tag` is left alone.
+    fn skips(&self, tag: &Tag) -> bool {
+        match tag {
+            Tag::Link(..) => self.skip_links,
+            Tag::Image(..) => self.skip_images,
+            Tag::Heading(..) => self.skip_headings,
+            _ => false,
+        }
+    }
+
+    fn get_title(&self, term: &Term) -> Option<String> {
+        if !self.titles {
+            return None;
+        }
+        match (&term.signature, &term.summary) {
+            (Some(signature), Some(summary)) => Some(format!("{}\n{}", signature, summary)),
+            (Some(text), None) | (None, Some(text)) => Some(text.clone()),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Reports inline code which looks like a term but is not in the lookup.
+pub struct Reporter {
+    pattern: Regex,
+    suggestions: usize,
+}
+
+impl Reporter {
+    pub fn new(config: &Config, pattern: Option<&Regex>) -> Option<Reporter> {
+        if !config.verbose {
+            return None;
+        }
+        pattern.map(|pattern| Reporter {
+            pattern: pattern.clone(),
+            suggestions: config.suggestions,
+        })
+    }
+
+    /// Reports an unknown `key`, or one which was only linked to its `parent` because the
+    /// member is unknown.
+    fn report(
+        &self,
+        lookup: &HashMap<String, Term>,
+        text: &str,
+        key: &str,
+        parent: Option<&Term>,
+        line: usize,
+        out: &mut String,
+    ) {
+        if !self.pattern.is_match(key) {
+            return;
+        }
+        match parent {
+            Some(parent) => {
+                let stem = parent.term.trim_end_matches("()");
+                let member = key.trim_end_matches("()")[stem.len()..].trim_start_matches('.');
+                out.push_str(&format!(
+                    "  line {}: `{}` linked to `{}`, unknown member `{}`",
+                    line, text, parent.term, member
+                ));
+            }
+            None => out.push_str(&format!("  line {}: Unknown term `{}`", line, text)),
+        }
+        let suggestions = get_suggestions(lookup, key, self.suggestions);
+        if !suggestions.is_empty() {
+            let suggestions: Vec<String> = suggestions.iter().map(|s| format!("`{}`", s)).collect();
+            out.push_str(&format!(", did you mean {}?", suggestions.join(", ")));
+        }
+        out.push('\n');
+    }
+}
+
+fn get_edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { diagonal } else { diagonal + 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = cost.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The closest known terms, only terms within a third of the length of `key` are considered.
+fn get_suggestions<'a>(lookup: &'a HashMap<String, Term>, key: &str, max: usize) -> Vec<&'a str> {
+    let limit = (key.chars().count() / 3).max(1);
+    let mut candidates: Vec<(usize, &str)> = lookup
+        .keys()
+        .map(|term| (get_edit_distance(key, term), term.as_str()))
+        .filter(|(distance, _)| *distance <= limit)
+        .collect();
+    candidates.sort();
+    candidates
+        .into_iter()
+        .take(max)
+        .map(|(_, term)| term)
+        .collect()
+}
+
+fn get_line(source: &str, offset: usize) -> usize {
+    source[..offset].matches('\n').count() + 1
+}
+
+pub fn load_terms(path: &Path) -> Result<HashMap<String, Term>> {
+    let data = fs::read_to_string(path)
+        .map_err(|e| Error::msg(format!("Unable to read {}: {}", path.display(), e)))?;
+    let terms = TermsFile::parse(&data)
+        .map_err(|e| Error::msg(format!("Unable to parse {}: {}", path.display(), e)))?
+        .terms;
     let mut res = HashMap::<String, Term>::new();
     res.reserve(terms.len());
 
@@ -34,67 +496,409 @@ pub fn load_config() -> HashMap<String, Term> {
         res.insert(String::from(term.term.as_str()), term);
     }
 
-    res
+    Ok(res)
+}
+
+/// Turns inline code into the key of a term: `struct tm_foo_t *` becomes `tm_foo_t`,
+/// `tm_foo_api->bar(x)` becomes `tm_foo_api.bar()` and `TM_STRHASH("x")` becomes `TM_STRHASH()`.
+pub fn normalize(text: &str) -> String {
+    let arguments = Regex::new(r"\(.*\)").unwrap();
+    let decorations = Regex::new(r"\b(struct|union|enum|const)\b|[*&;]").unwrap();
+    let member = Regex::new(r"\s*(->|\.|::)\s*").unwrap();
+    // Quoted code such as `"tm_foo_t"` refers to the term as well.
+    let key = text.replace('"', "");
+    let key = arguments.replace_all(&key, "()");
+    let key = decorations.replace_all(&key, " ");
+    let key = member.replace_all(&key, ".");
+    // A declaration such as `tm_allocator_i *a` refers to its type.
+    key.split_whitespace().next().unwrap_or("").to_string()
+}
+
+/// Finds the term of a normalized key, with or without `()`. Unknown members fall back to the
+/// longest known prefix, `tm_foo_api.unknown()` links to `tm_foo_api`.
+pub fn find_term<'a>(lookup: &'a HashMap<String, Term>, key: &str) -> Option<&'a Term> {
+    let mut key = key;
+    loop {
+        let stem = key.trim_end_matches("()");
+        let term = lookup
+            .get(key)
+            .or_else(|| lookup.get(stem))
+            .or_else(|| lookup.get(&format!("{}()", stem)));
+        if term.is_some() {
+            return term;
+        }
+        key = &stem[..stem.rfind('.')?];
+    }
+}
+
+const C_KEYWORDS: &[&str] = &[
+    "break", "case", "const", "continue", "default", "do", "else", "enum", "extern", "for", "goto",
+    "if", "inline", "register", "restrict", "return", "sizeof", "static", "struct", "switch",
+    "typedef", "union", "volatile", "while",
+];
+const C_TYPES: &[&str] = &[
+    "bool", "char", "double", "float", "int", "long", "short", "signed", "unsigned", "void",
+    "int8_t", "int16_t", "int32_t", "int64_t", "uint8_t", "uint16_t", "uint32_t", "uint64_t",
+    "size_t",
+];
+const C_LITERALS: &[&str] = &["true", "false", "NULL"];
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
 }
 
-pub fn process(lookup: &HashMap<String, Term>, chapter: String) -> Option<String> {
+fn get_span(class: &str, text: &str) -> String {
+    format!(
+        "<span class=\"hljs-{}\">{}</span>",
+        class,
+        escape_html(text)
+    )
+}
+
+/// Renders a C code block as html with the known terms linked. highlight.js would throw the
+/// links away, so the block is highlighted here with its classes and marked as `nohighlight`.
+fn link_code_block(
+    linker: &Linker,
+    set: &TermSet,
+    code: &str,
+    used: &mut BTreeSet<String>,
+) -> String {
+    let re = Regex::new(
+        r#"(?s)(?P<comment>//[^\n]*|/\*.*?\*/)|(?P<string>"(?:\\.|[^"\\\n])*"|'(?:\\.|[^'\\\n])*')|(?P<meta>(?m:^)[ \t]*#[ \t]*[A-Za-z_]+)|(?P<number>\b[0-9][0-9A-Za-z_.]*)|(?P<ident>[A-Za-z_][A-Za-z0-9_]*)"#,
+    )
+    .unwrap();
+    let mut html = String::new();
+    let mut last = 0;
+    // The term before `->` or `.`, the next identifier is looked up as its member.
+    let mut parent: Option<String> = None;
+    for cap in re.captures_iter(code) {
+        let token = cap.get(0).unwrap();
+        let gap = &code[last..token.start()];
+        html.push_str(&escape_html(gap));
+        last = token.end();
+        let text = token.as_str();
+
+        let ident = match cap.name("ident") {
+            Some(_) => text,
+            None => {
+                let class = if cap.name("comment").is_some() {
+                    "comment"
+                } else if cap.name("string").is_some() {
+                    "string"
+                } else if cap.name("meta").is_some() {
+                    "meta"
+                } else {
+                    "number"
+                };
+                html.push_str(&get_span(class, text));
+                parent = None;
+                continue;
+            }
+        };
+
+        let term = match parent.take() {
+            // Members are looked up exactly, an unknown one must not link to its parent.
+            Some(parent) if gap.trim() == "->" || gap.trim() == "." => {
+                let key = format!("{}.{}", parent, ident);
+                set.lookup
+                    .get(&key)
+                    .or_else(|| set.lookup.get(&format!("{}()", key)))
+            }
+            _ => {
+                let term = find_term(&set.lookup, ident);
+                if term.is_some() {
+                    parent = Some(ident.to_string());
+                }
+                term
+            }
+        };
+        if let Some(term) = term {
+            used.insert(term.term.clone());
+            let title = match linker.get_title(term) {
+                Some(title) => format!(" title=\"{}\"", escape_html(&title)),
+                None => String::new(),
+            };
+            html.push_str(&format!(
+                "<a href=\"{}\"{}>{}</a>",
+                escape_html(&set.get_link(term)),
+                title,
+                escape_html(ident)
+            ));
+        } else if C_KEYWORDS.contains(&ident) {
+            html.push_str(&get_span("keyword", ident));
+        } else if C_TYPES.contains(&ident) {
+            html.push_str(&get_span("type", ident));
+        } else if C_LITERALS.contains(&ident) {
+            html.push_str(&get_span("literal", ident));
+        } else {
+            html.push_str(&escape_html(ident));
+        }
+    }
+    html.push_str(&escape_html(&code[last..]));
+
+    // Kept on one line so the html block survives blank lines and list indentation.
+    let html = html.trim_end_matches('\n').replace('\n', "&#10;");
+    format!("\n<pre><code class=\"nohighlight\">{}</code></pre>\n", html)
+}
+
+/// The state of linking one chapter.
+struct ChapterLinker<'a, 'b> {
+    linker: &'b Linker<'b>,
+    set: &'b TermSet,
+    source: &'a str,
+    /// Lines before `source`, taken by the front matter.
+    skipped_lines: usize,
+    events: Vec<Event<'a>>,
+    used: BTreeSet<String>,
+    unknown: String,
+    errors: Vec<String>,
+    /// Consecutive text with its offsets, `[[` and `]]` end up in events of their own.
+    text: Vec<(CowStr<'a>, usize)>,
+}
+
+impl<'a, 'b> ChapterLinker<'a, 'b> {
+    fn get_line(&self, offset: usize) -> usize {
+        self.skipped_lines + get_line(self.source, offset)
+    }
+
+    fn push_link(&mut self, term: &Term, content: Event<'a>) {
+        self.used.insert(term.term.clone());
+        let link: CowStr = self.set.get_link(term).into();
+        // Quotes would end the markdown title early.
+        let title: CowStr = match self.linker.get_title(term) {
+            Some(title) => title.replace('"', "&quot;").into(),
+            None => CowStr::Borrowed(""),
+        };
+        self.events.push(Event::Start(Tag::Link(
+            LinkType::Inline,
+            link.clone(),
+            title.clone(),
+        )));
+        self.events.push(content);
+        self.events
+            .push(Event::End(Tag::Link(LinkType::Inline, link, title)));
+    }
+
+    fn push_text(&mut self, text: &str) {
+        if !text.is_empty() {
+            self.events.push(Event::Text(text.to_string().into()));
+        }
+    }
+
+    /// `!tm_foo` stays plain code, only the `!` is removed. This also holds for terms which
+    /// are unknown, e.g. since they were removed from the terms.json.
+    fn strip_escape(&self, text: &str) -> Option<String> {
+        let rest = text.strip_prefix('!')?;
+        let key = normalize(rest);
+        let pattern = self.linker.unknown_pattern.as_ref();
+        if pattern.map_or(false, |pattern| pattern.is_match(&key))
+            || find_term(&self.set.lookup, &key).is_some()
+        {
+            Some(rest.to_string())
+        } else {
+            None
+        }
+    }
+
+    fn push_code(&mut self, text: CowStr<'a>, offset: usize) {
+        let set = self.set;
+        if let Some(rest) = self.strip_escape(&text) {
+            self.events.push(Event::Code(rest.into()));
+            return;
+        }
+        let key = normalize(&text);
+        let term = find_term(&set.lookup, &key);
+        if let Some(reporter) = &self.linker.reporter {
+            // A term found through the prefix fallback hides a typo in the member.
+            let fallback =
+                term.filter(|term| term.term.trim_end_matches("()") != key.trim_end_matches("()"));
+            if term.is_none() || fallback.is_some() {
+                let line = self.get_line(offset);
+                reporter.report(&set.lookup, &text, &key, fallback, line, &mut self.unknown);
+            }
+        }
+        match term {
+            Some(term) => self.push_link(term, Event::Code(text)),
+            None => self.events.push(Event::Code(text)),
+        }
+    }
+
+    /// Resolves the explicit links in the pending text. Unlike inline code they have to match a
+    /// term exactly.
+    fn flush_text(&mut self) {
+        if self.text.is_empty() {
+            return;
+        }
+        let pieces = std::mem::take(&mut self.text);
+        let text: String = pieces.iter().map(|(text, _)| text.as_ref()).collect();
+        let re = Regex::new(EXPLICIT_LINK).unwrap();
+        if !re.is_match(&text) {
+            self.events
+                .extend(pieces.into_iter().map(|(text, _)| Event::Text(text)));
+            return;
+        }
+
+        let set = self.set;
+        let mut last = 0;
+        for cap in re.captures_iter(&text) {
+            let m = cap.get(0).unwrap();
+            self.push_text(&text[last..m.start()]);
+            last = m.end();
+            let name = cap[1].trim();
+            let key = normalize(name);
+            let stem = key.trim_end_matches("()");
+            let term = set
+                .lookup
+                .get(&key)
+                .or_else(|| set.lookup.get(stem))
+                .or_else(|| set.lookup.get(&format!("{}()", stem)));
+            match term {
+                Some(term) => {
+                    let content = match cap.get(2) {
+                        Some(label) => Event::Text(label.as_str().trim().to_string().into()),
+                        None => Event::Code(name.to_string().into()),
+                    };
+                    self.push_link(term, content);
+                }
+                None => {
+                    let mut start = 0;
+                    let mut offset = pieces[0].1;
+                    for (piece, piece_offset) in &pieces {
+                        offset = *piece_offset;
+                        start += piece.len();
+                        if start > m.start() {
+                            break;
+                        }
+                    }
+                    let line = self.get_line(offset);
+                    self.errors
+                        .push(format!("  line {}: Unknown term in `{}`", line, m.as_str()));
+                    self.push_text(m.as_str());
+                }
+            }
+        }
+        self.push_text(&text[last..]);
+    }
+}
+
+pub fn process(linker: &Linker, chapter: &Chapter) -> Result<String> {
     let mut opts = Options::empty();
     opts.insert(Options::ENABLE_TABLES);
     opts.insert(Options::ENABLE_FOOTNOTES);
     opts.insert(Options::ENABLE_STRIKETHROUGH);
     opts.insert(Options::ENABLE_TASKLISTS);
 
-    let iter = Parser::new_ext(chapter.as_str(), opts).into_iter();
-    let mut events = Vec::<Event>::new();
-    for i in iter {
+    let (front_matter, source, skipped_lines) = split_front_matter(&chapter.content);
+    let mut state = ChapterLinker {
+        linker,
+        set: linker.get_term_set(chapter, front_matter.get("terms")),
+        source,
+        skipped_lines,
+        events: Vec::new(),
+        used: BTreeSet::new(),
+        unknown: String::new(),
+        errors: Vec::new(),
+        text: Vec::new(),
+    };
+    let toggle = Regex::new(TOGGLE).unwrap();
+    let mut enabled = true;
+    let mut code_block: Option<String> = None;
+    let mut in_code_block = false;
+    // Inside links, images or headings, see `Linker::skips`.
+    let mut skipped_depth = 0;
+    let iter = Parser::new_ext(source, opts).into_offset_iter();
+    for (i, range) in iter {
+        if let Event::Html(ref html) = i {
+            if let Some(cap) = toggle.captures_iter(html).last() {
+                state.flush_text();
+                enabled = &cap[1] == "on";
+                state.events.push(i);
+                continue;
+            }
+        }
+        if !enabled {
+            state.events.push(i);
+            continue;
+        }
+        match i {
+            Event::Start(ref tag) if linker.skips(tag) => {
+                state.flush_text();
+                skipped_depth += 1;
+                state.events.push(i);
+                continue;
+            }
+            Event::End(ref tag) if linker.skips(tag) => {
+                skipped_depth -= 1;
+                state.events.push(i);
+                continue;
+            }
+            Event::Code(text) if skipped_depth > 0 => {
+                let text = state.strip_escape(&text).map_or(text, CowStr::from);
+                state.events.push(Event::Code(text));
+                continue;
+            }
+            _ if skipped_depth > 0 => {
+                state.events.push(i);
+                continue;
+            }
+            _ => {}
+        }
         match i {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(ref info)))
+                if linker
+                    .code_block_languages
+                    .iter()
+                    .any(|l| Some(l.as_str()) == info.split_whitespace().next()) =>
+            {
+                state.flush_text();
+                code_block = Some(String::new());
+            }
+            Event::Text(ref text) if code_block.is_some() => {
+                code_block.as_mut().unwrap().push_str(text);
+            }
+            Event::End(Tag::CodeBlock(_)) if code_block.is_some() => {
+                let code = code_block.take().unwrap();
+                let html = link_code_block(linker, state.set, &code, &mut state.used);
+                state.events.push(Event::Html(html.into()));
+            }
+            Event::Text(text) if !in_code_block => {
+                state.text.push((text, range.start));
+            }
             Event::Code(text) => {
-                let key = text.to_string().replace("\"", "");
-                let re = Regex::new(r"\(.*\)").unwrap();
-                let key = key.replace(r"", "");
-                let key = re.replace_all(&key, "()").to_string();
-                let alt_key = format!("{}()", key);
-                if lookup.contains_key(&key) || lookup.contains_key(&alt_key) {
-                    let term = lookup.get(&key);
-                    let term = if term.is_none() {
-                        lookup.get(&alt_key).unwrap()
-                    } else {
-                        term.unwrap()
-                    };
-
-                    let link = format!("{}{}.html#{}", "{{docs}}", term.path, term.name);
-                    let b = link.into_boxed_str();
-                    let e = Event::Start(Tag::Link(
-                        LinkType::Inline,
-                        text.clone(),
-                        CowStr::Borrowed(""),
-                    ));
-                    events.push(e);
-                    let e = Event::Code(text.clone());
-                    events.push(e);
-                    let e = Event::End(Tag::Link(
-                        LinkType::Inline,
-                        CowStr::Boxed(b),
-                        CowStr::Borrowed(""),
-                    ));
-                    events.push(e);
-                } else {
-                    // TODO: enable verbose
-                    //eprint!("Warning: Unknown tag: `{}`\n", text);
-                    let e = Event::Code(text.clone());
-                    events.push(e);
-                }
+                state.flush_text();
+                state.push_code(text, range.start);
             }
             _ => {
-                events.push(i);
+                state.flush_text();
+                match i {
+                    Event::Start(Tag::CodeBlock(_)) => in_code_block = true,
+                    Event::End(Tag::CodeBlock(_)) => in_code_block = false,
+                    _ => {}
+                }
+                state.events.push(i);
             }
         };
     }
+    state.flush_text();
+
+    linker.add_usage(state.set, state.used, chapter);
+
+    warn_chapter(chapter, "Unknown terms", &state.unknown);
+    if !state.errors.is_empty() {
+        return Err(Error::msg(format!(
+            "Unknown terms in explicit links of {}:\n{}",
+            get_chapter_name(chapter),
+            state.errors.join("\n")
+        )));
+    }
 
     let mut buf = String::new();
-    cmark(events.into_iter(), &mut buf).unwrap();
+    cmark(state.events.iter(), &mut buf).unwrap();
 
-    Some(buf)
+    Ok(buf)
 }
 
 impl Preprocessor for AutoDoc {
@@ -102,24 +906,287 @@ impl Preprocessor for AutoDoc {
         "auto_doc"
     }
 
-    fn run(&self, _: &PreprocessorContext, mut book: Book) -> Result<Book> {
-        let res = None;
-        let lookup = load_config();
-        book.for_each_mut(|item: &mut BookItem| {
-            if let Some(Err(_)) = res {
-                return;
+    fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
+        let config = load_config(ctx);
+        let sets = load_term_sets(ctx, &config)?;
+        let linker = Linker::new(&sets, &config, &ctx.renderer);
+        for_each_chapter_mut(&mut book.sections, &|chapter: &mut Chapter| {
+            // The API index is generated once all other chapters are linked.
+            if config.api_index.is_some() && config.api_index == chapter.source_path {
+                return Ok(());
             }
+            chapter.content = process(&linker, chapter)?;
+            Ok(())
+        })?;
 
-            if let BookItem::Chapter(ref mut chapter) = *item {
-                let content = chapter.content.to_string();
-                chapter.content = process(&lookup, content).unwrap();
+        if let Some(page_path) = &config.api_index {
+            let mut chapters = Vec::<(PathBuf, String)>::new();
+            for item in book.iter() {
+                if let BookItem::Chapter(ref chapter) = *item {
+                    if let Some(path) = &chapter.path {
+                        chapters.push((path.clone(), chapter.name.clone()));
+                    }
+                }
             }
-        });
+            if let Some(page) = linker.render_api_index(&config, page_path, &chapters) {
+                upsert_generated_chapter(
+                    &mut book,
+                    page_path,
+                    &config.api_index_title,
+                    API_INDEX_MARKER,
+                    page,
+                );
+            }
+        }
 
-        res.unwrap_or(Ok(())).map(|_| book)
+        Ok(book)
     }
 
     fn supports_renderer(&self, _renderer: &str) -> bool {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_term(term: &str) -> Term {
+        Term {
+            term: term.to_string(),
+            path: String::from("foundation/foo.h"),
+            file: String::from("foo.h"),
+            name: term.trim_end_matches("()").to_string(),
+            id: serde_json::Number::from(0),
+            signature: None,
+            summary: None,
+        }
+    }
+
+    fn get_lookup(terms: &[&str]) -> HashMap<String, Term> {
+        terms
+            .iter()
+            .map(|term| (term.to_string(), get_term(term)))
+            .collect()
+    }
+
+    #[test]
+    fn normalize_and_find_term() {
+        let lookup = get_lookup(&[
+            "tm_entity_api",
+            "tm_entity_api.create_truth_types()",
+            "tm_allocator_i",
+            "tm_foo_t",
+            "TM_STRHASH()",
+            "tm_foo_api",
+            "tm_foo_api.bar()",
+            "tm_foo_api.version",
+        ]);
+        // Inline code as it is written in the book, its key and the term it links to.
+        let table = [
+            (
+                "tm_entity_api->create_truth_types",
+                "tm_entity_api.create_truth_types",
+                Some("tm_entity_api.create_truth_types()"),
+            ),
+            (
+                "tm_entity_api->create_truth_types(tt)",
+                "tm_entity_api.create_truth_types()",
+                Some("tm_entity_api.create_truth_types()"),
+            ),
+            (
+                "tm_allocator_i *a",
+                "tm_allocator_i",
+                Some("tm_allocator_i"),
+            ),
+            ("struct tm_foo_t", "tm_foo_t", Some("tm_foo_t")),
+            ("const struct tm_foo_t *", "tm_foo_t", Some("tm_foo_t")),
+            ("TM_STRHASH(\"x\")", "TM_STRHASH()", Some("TM_STRHASH()")),
+            ("TM_STRHASH", "TM_STRHASH", Some("TM_STRHASH()")),
+            (
+                "tm_foo_api.bar()",
+                "tm_foo_api.bar()",
+                Some("tm_foo_api.bar()"),
+            ),
+            (
+                "tm_foo_api::bar",
+                "tm_foo_api.bar",
+                Some("tm_foo_api.bar()"),
+            ),
+            (
+                "tm_foo_api.version",
+                "tm_foo_api.version",
+                Some("tm_foo_api.version"),
+            ),
+            ("\"tm_foo_t\"", "tm_foo_t", Some("tm_foo_t")),
+            // Unknown members fall back to the longest known prefix.
+            (
+                "tm_foo_api->unknown()",
+                "tm_foo_api.unknown()",
+                Some("tm_foo_api"),
+            ),
+            ("tm_foo_api.a.b", "tm_foo_api.a.b", Some("tm_foo_api")),
+            ("tm_unknown_api.bar()", "tm_unknown_api.bar()", None),
+            ("int x", "int", None),
+        ];
+        for (text, key, term) in table.iter() {
+            assert_eq!(normalize(text), *key, "normalize({:?})", text);
+            assert_eq!(
+                find_term(&lookup, key).map(|t| t.term.as_str()),
+                *term,
+                "find_term({:?})",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn front_matter() {
+        let (keys, rest, lines) = split_front_matter("---\nterms: 2021.11\n---\n# Title\n");
+        assert_eq!(keys.get("terms").map(String::as_str), Some("2021.11"));
+        assert_eq!(rest, "# Title\n");
+        assert_eq!(lines, 3);
+        // A thematic break followed by a setext heading stays in the chapter.
+        let content = "---\nNote: read this first\n---\nText\n";
+        let (keys, rest, lines) = split_front_matter(content);
+        assert!(keys.is_empty());
+        assert_eq!(rest, content);
+        assert_eq!(lines, 0);
+    }
+
+    fn run_process(content: &str) -> String {
+        let config = Config::default();
+        let mut sets = BTreeMap::<String, TermSet>::new();
+        sets.insert(
+            String::from(DEFAULT_TERM_SET),
+            TermSet {
+                name: String::from(DEFAULT_TERM_SET),
+                lookup: get_lookup(&["tm_foo_t", "tm_bar_api"]),
+                base_url: String::from("https://docs/"),
+            },
+        );
+        let linker = Linker::new(&sets, &config, "markdown");
+        let chapter = Chapter::new("Test", content.to_string(), "test.md", Vec::new());
+        process(&linker, &chapter).unwrap()
+    }
+
+    /// How often the chapter links to `tm_foo_t`.
+    fn count_links(output: &str) -> usize {
+        output
+            .matches("https://docs/foundation/foo.h.html#tm_foo_t")
+            .count()
+    }
+
+    #[test]
+    fn process_skips() {
+        // Inline code with the number of links expected for it.
+        let table = [
+            ("Uses `tm_foo_t` here.", 1),
+            ("See [the `tm_foo_t` docs](other.md).", 0),
+            ("![`tm_foo_t`](image.png)", 0),
+            ("# The `tm_foo_t` struct", 0),
+            ("| Type |\n| --- |\n| `tm_foo_t` |", 1),
+            ("Text[^1]\n\n[^1]: Uses `tm_foo_t`.", 1),
+            ("> - [ ] Check `tm_foo_t`", 1),
+            ("Keep `!tm_foo_t` plain.", 0),
+        ];
+        for (content, links) in table.iter() {
+            assert_eq!(count_links(&run_process(content)), *links, "{:?}", content);
+        }
+    }
+
+    #[test]
+    fn process_escape_in_skipped() {
+        for content in [
+            "# The `!tm_foo_t` struct",
+            "See [the `!tm_foo_t` docs](other.md).",
+            "Keep `!tm_foo_t` plain.",
+        ]
+        .iter()
+        {
+            let output = run_process(content);
+            assert!(!output.contains("!tm_foo_t"), "{:?}: {}", content, output);
+            assert!(output.contains("tm_foo_t"), "{:?}: {}", content, output);
+            assert_eq!(count_links(&output), 0, "{:?}", content);
+        }
+        // Unknown terms lose their `!` too, other code keeps it.
+        let output =
+            run_process("# `!tm_old_api` and `!tm_old_api.bar()`\n\n`!TM_OLD` and `!done`");
+        assert!(!output.contains("!tm_old_api"), "{}", output);
+        assert!(!output.contains("!TM_OLD"), "{}", output);
+        assert!(output.contains("tm_old_api.bar()"), "{}", output);
+        assert!(output.contains("!done"), "{}", output);
+    }
+
+    #[test]
+    fn report_unknown_members() {
+        let config = Config {
+            verbose: true,
+            ..Config::default()
+        };
+        let pattern = Regex::new(&config.unknown_pattern).unwrap();
+        let reporter = Reporter::new(&config, Some(&pattern)).unwrap();
+        let lookup = get_lookup(&["tm_entity_api", "tm_entity_api.create_truth_types()"]);
+        let mut out = String::new();
+        let key = normalize("tm_entity_api->typo()");
+        let parent = find_term(&lookup, &key);
+        reporter.report(&lookup, "tm_entity_api->typo()", &key, parent, 3, &mut out);
+        reporter.report(&lookup, "tm_entty_api", "tm_entty_api", None, 4, &mut out);
+        let expected = [
+            "  line 3: `tm_entity_api->typo()` linked to `tm_entity_api`, unknown member `typo`\n",
+            "  line 4: Unknown term `tm_entty_api`, did you mean `tm_entity_api`?\n",
+        ];
+        assert_eq!(out, expected.concat());
+    }
+
+    #[test]
+    fn code_block_members() {
+        let config = Config {
+            code_blocks: true,
+            ..Config::default()
+        };
+        let mut sets = BTreeMap::<String, TermSet>::new();
+        sets.insert(
+            String::from(DEFAULT_TERM_SET),
+            TermSet {
+                name: String::from(DEFAULT_TERM_SET),
+                lookup: get_lookup(&["tm_global_api_registry", "tm_foo_api", "tm_foo_api.bar()"]),
+                base_url: String::new(),
+            },
+        );
+        let linker = Linker::new(&sets, &config, "html");
+        let mut used = BTreeSet::new();
+        let html = link_code_block(
+            &linker,
+            &sets[DEFAULT_TERM_SET],
+            "tm_global_api_registry->get(x);\ntm_foo_api->bar();\n",
+            &mut used,
+        );
+        assert!(
+            html.contains(">tm_global_api_registry</a>-&gt;get("),
+            "{}",
+            html
+        );
+        assert!(html.contains(">tm_foo_api</a>-&gt;<a"), "{}", html);
+        assert!(html.contains(">bar</a>("), "{}", html);
+        assert_eq!(
+            used.into_iter().collect::<Vec<_>>(),
+            vec!["tm_foo_api", "tm_foo_api.bar()", "tm_global_api_registry"]
+        );
+    }
+
+    #[test]
+    fn load_terms_errors() {
+        let dir = std::env::temp_dir().join(format!("tmbook-auto-doc-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("terms.json");
+        let error = load_terms(&path).unwrap_err().to_string();
+        assert!(error.starts_with("Unable to read "), "{}", error);
+        assert!(error.contains("terms.json"), "{}", error);
+        fs::write(&path, "{\"terms\": 3}").unwrap();
+        let error = load_terms(&path).unwrap_err().to_string();
+        assert!(error.starts_with("Unable to parse "), "{}", error);
+        assert!(error.contains("terms.json"), "{}", error);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
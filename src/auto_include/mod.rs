@@ -1,7 +1,7 @@
 extern crate mdbook;
 extern crate serde_json;
 
-use mdbook::book::{Book, BookItem};
+use mdbook::book::{Book, Chapter};
 use mdbook::errors::Result;
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
 use pulldown_cmark::{CowStr, Event, Options, Parser, Tag};
@@ -15,7 +15,10 @@ use std::process::{Command, Stdio};
 #[cfg(unix)]
 use std::os::unix::fs::PermissionsExt;
 
-use crate::utility::get_clang_format;
+use crate::utility::{
+    check_snippets_lock, for_each_chapter_mut, get_clang_format, get_snippet_source_env,
+    TM_BOOK_LOCK_FILE,
+};
 pub struct AutoInclude;
 
 fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<std::fs::File>>>
@@ -78,6 +81,18 @@ fn replace_env(path: String) -> String {
     res
 }
 
+fn replace_snippet_source(path: String) -> String {
+    let regex = r"^(@([A-Za-z0-9_-]+))";
+    let re = Regex::new(regex).unwrap();
+    if let Some(cap) = re.captures(&path) {
+        match env::var(get_snippet_source_env(&cap[2])) {
+            Ok(value) => return path.replacen(&cap[1], value.as_str(), 1),
+            Err(_) => eprintln!("Warning: Unknown snippet source: `{}`", &cap[2]),
+        }
+    }
+    path
+}
+
 fn process_term(
     source: io::Lines<io::BufReader<std::fs::File>>,
     requested_tag: &String,
@@ -169,13 +184,13 @@ fn process_clang_format(source: String) -> String {
 
 fn find_broken(chapter: &String){
 
-        let mut regex = r"\{\{insert_code\(([/aA-zZ_.0-9]+),([/aA-zZ_0-9]+)\}\}";
+        let mut regex = r"\{\{insert_code\(([@/A-Za-z0-9_.-]+),([/aA-zZ_0-9]+)\}\}";
         let mut re = Regex::new(regex).unwrap();
         let str = chapter.clone();
         let res = if re.is_match(&str) {
             re.captures_iter(&str)
         } else {
-            regex = r"\{\{insert_code\(([/aA-zZ_.0-9]+),([/aA-zZ_0-9]+),([aA-zZ_0-9]+)\}\}";
+            regex = r"\{\{insert_code\(([@/A-Za-z0-9_.-]+),([/aA-zZ_0-9]+),([aA-zZ_0-9]+)\}\}";
             re = Regex::new(regex).unwrap();
             re.captures_iter(&str)
         };
@@ -190,13 +205,13 @@ fn find_term(mut chapter: String) -> String {
 
     find_broken(&chapter);
 
-    let mut regex = r"\{\{insert_code\(([/aA-zZ_.0-9]+),([/aA-zZ_0-9]+)\)\}\}";
+    let mut regex = r"\{\{insert_code\(([@/A-Za-z0-9_.-]+),([/aA-zZ_0-9]+)\)\}\}";
     let mut re = Regex::new(regex).unwrap();
     let str = chapter.clone();
     let res = if re.is_match(&str) {
         re.captures_iter(&str)
     } else {
-        regex = r"\{\{insert_code\(([/aA-zZ_.0-9]+),([/aA-zZ_0-9]+),([aA-zZ_0-9]+)\)\}\}";
+        regex = r"\{\{insert_code\(([@/A-Za-z0-9_.-]+),([/aA-zZ_0-9]+),([aA-zZ_0-9]+)\)\}\}";
         re = Regex::new(regex).unwrap();
         re.captures_iter(&str)
     };
@@ -205,7 +220,7 @@ fn find_term(mut chapter: String) -> String {
             let requested_tag = cap[2].to_string();
             let path_formated = cap[1].to_string().replace("/",std::path::MAIN_SEPARATOR.to_string().as_str());
             let path_formated = path_formated.replace("\\",std::path::MAIN_SEPARATOR.to_string().as_str());
-            let path = replace_env(path_formated);
+            let path = replace_env(replace_snippet_source(path_formated));
             let path = Path::new(&path);
             if path.exists() {
                 if let Ok(lines) = read_lines(path) {
@@ -218,14 +233,14 @@ fn find_term(mut chapter: String) -> String {
             }
         }
     } else {
-        regex = r"\{\{insert_code\(([/aA-zZ_.0-9]+)\)\}\}";
+        regex = r"\{\{insert_code\(([@/A-Za-z0-9_.-]+)\)\}\}";
         let nre = Regex::new(regex).unwrap();
         let results = nre.captures_iter(&str);
         for cap in results {
             let mut tag_content = Vec::<String>::new();
             let path_formated = cap[1].to_string().replace("/",std::path::MAIN_SEPARATOR.to_string().as_str());
             let path_formated = path_formated.replace("\\",std::path::MAIN_SEPARATOR.to_string().as_str());
-            let path = replace_env(path_formated);
+            let path = replace_env(replace_snippet_source(path_formated));
             let path = Path::new(&path);
             if path.exists() {
                 if let Ok(lines) = read_lines(path) {
@@ -292,19 +307,14 @@ impl Preprocessor for AutoInclude {
     }
 
     fn run(&self, _: &PreprocessorContext, mut book: Book) -> Result<Book> {
-        let res = None;
-        book.for_each_mut(|item: &mut BookItem| {
-            if let Some(Err(_)) = res {
-                return;
-            }
-
-            if let BookItem::Chapter(ref mut chapter) = *item {
-                let content = chapter.content.to_string();
-                chapter.content = process(content).unwrap();
-            }
-        });
+        check_snippets_lock(Path::new(TM_BOOK_LOCK_FILE));
+        for_each_chapter_mut(&mut book.sections, &|chapter: &mut Chapter| {
+            let content = chapter.content.to_string();
+            chapter.content = process(content).unwrap();
+            Ok(())
+        })?;
 
-        res.unwrap_or(Ok(())).map(|_| book)
+        Ok(book)
     }
 
     fn supports_renderer(&self, _renderer: &str) -> bool {
@@ -10,8 +10,9 @@ use std::io;
 use std::path::Path;
 use std::process::{self, Stdio};
 use utility::{
-    check_and_set_or_download_book_code_snippets, get_bin_dir, get_clang_format_url, get_mdbook,
-    get_mdbook_linkcheck_url, get_mdbook_toc_url, get_mdbook_url,
+    check_and_set_or_download_book_code_snippets, check_and_set_snippet_sources, get_bin_dir,
+    get_clang_format_url, get_jobs, get_mdbook, get_mdbook_linkcheck_url, get_mdbook_toc_url,
+    get_mdbook_url, get_profile, set_profile, update_snippets,
 };
 
 use tokio::io::{AsyncBufReadExt, BufReader};
@@ -21,10 +22,14 @@ mod authors;
 mod auto_doc;
 mod auto_include;
 mod replace_path;
+mod terms;
 mod utility;
-use replace_path::ReplacePaths;
+use replace_path::{Context, ReplacePaths};
+use terms::generate_terms;
 
-use crate::utility::{fetch_url, unzip, TM_BOOKS_REPO};
+use crate::utility::{
+    fetch_url, unzip, TM_BOOKS_REPO, TM_BOOK_LOCK_FILE, TM_BOOK_SNIPPET_SOURCES_DIR,
+};
 
 #[derive(PartialEq)]
 enum PreType {
@@ -82,6 +87,46 @@ pub fn make_app() -> App<'static> {
                 .about("Check whether a renderer is supported by this preprocessor"),
         );
 
+    let snippets = App::new("snippets")
+        .about("Manages the code snippet repositories")
+        .subcommand(
+            App::new("update")
+                .about("Fetches the snippet repositories and bumps the commits in tmbook.lock"),
+        );
+
+    let terms = App::new("terms")
+        .about("Manages the terms.json used by auto_doc")
+        .subcommand(
+            App::new("generate")
+                .about("Generates the terms from The Machinery API headers")
+                .arg(
+                    Arg::new("headers")
+                        .long("headers")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The headers folder of the SDK"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .takes_value(true)
+                        .default_value("./terms.json")
+                        .help("The generated terms file"),
+                )
+                .arg(
+                    Arg::new("sdk-version")
+                        .long("sdk-version")
+                        .takes_value(true)
+                        .help("The SDK version recorded in the terms file"),
+                ),
+        );
+
+    let context = App::new("context")
+        .about("Inspects the context used by path_replacement")
+        .subcommand(
+            App::new("show").about("Prints the merged context values and where each came from"),
+        );
+
     App::new("tmbook")
         .version("1.0")
         .author("Our Machinery")
@@ -98,12 +143,30 @@ pub fn make_app() -> App<'static> {
                 .takes_value(true)
                 .help("Ensures the right folder for the binaries"),
         )
+        .arg(
+            Arg::new("jobs")
+                .long("jobs")
+                .short('j')
+                .takes_value(true)
+                .global(true)
+                .help("Number of threads used to process the chapters (default: one per cpu)"),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .takes_value(true)
+                .global(true)
+                .help("Loads context.<profile>.json over context.json (default: TMBOOK_PROFILE)"),
+        )
         .subcommand(path_replacement)
         .subcommand(auto_doc)
         .subcommand(auto_include)
         .subcommand(authors)
         .subcommand(toc)
         .subcommand(linkcheck)
+        .subcommand(snippets)
+        .subcommand(terms)
+        .subcommand(context)
 }
 
 #[tokio::main]
@@ -112,6 +175,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let alt_path = matches.value_of("bin-path");
 
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(get_jobs(matches.value_of("jobs")))
+        .build_global()
+        .unwrap();
+    set_profile(matches.value_of("profile"));
+
     let bin_dir = get_bin_dir(alt_path);
     {
         let zip_name = Path::new(get_mdbook_url()).file_name().unwrap();
@@ -158,7 +227,58 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
-    check_and_set_or_download_book_code_snippets(Path::new("./code_snippets"));
+    if let Some(sub_matches) = matches.subcommand_matches("context") {
+        if sub_matches.subcommand_matches("show").is_some() {
+            let profile = get_profile();
+            match &profile {
+                Some(profile) => println!("Profile: {}", profile),
+                None => println!("Profile: none"),
+            }
+            match Context::load(Path::new("."), profile.as_deref()) {
+                Ok(context) => context.show(),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("terms") {
+        if let Some(sub_args) = sub_matches.subcommand_matches("generate") {
+            let headers = sub_args.value_of("headers").expect("Required argument");
+            let output = sub_args.value_of("output").expect("Default argument");
+            let version = sub_args.value_of("sdk-version");
+            if let Err(e) = generate_terms(Path::new(headers), Path::new(output), version) {
+                eprintln!("Cannot generate {}: {}", output, e);
+                process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("snippets") {
+        if sub_matches.subcommand_matches("update").is_some() {
+            update_snippets(
+                Path::new("./code_snippets"),
+                Path::new("./book.toml"),
+                Path::new(TM_BOOK_SNIPPET_SOURCES_DIR),
+                Path::new(TM_BOOK_LOCK_FILE),
+            );
+        }
+        return Ok(());
+    }
+
+    check_and_set_or_download_book_code_snippets(
+        Path::new("./code_snippets"),
+        Path::new(TM_BOOK_LOCK_FILE),
+    );
+    check_and_set_snippet_sources(
+        Path::new("./book.toml"),
+        Path::new(TM_BOOK_SNIPPET_SOURCES_DIR),
+        Path::new(TM_BOOK_LOCK_FILE),
+    );
 
     if let Some(sub_matches) = matches.subcommand_matches("authors") {
         if let Some(sub_args) = sub_matches.subcommand_matches("supports") {
@@ -1,65 +1,531 @@
 extern crate mdbook;
 extern crate serde_json;
 
-use mdbook::book::{Book, BookItem};
-use mdbook::errors::Result;
+use mdbook::book::{Book, Chapter};
+use mdbook::errors::{Error, Result};
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
 use regex::Regex;
-//use std::convert::TryInto;
+use serde_json::Value;
+use std::collections::BTreeMap;
 use std::fs;
-//use toml::value::Table;
+use std::path::Path;
+
+use crate::utility::{for_each_chapter_mut, get_chapter_name, get_profile, warn_chapter};
 
 pub struct ReplacePaths;
 
-pub fn load_config() -> serde_json::Value {
-    let path = "./context.json";
-    let data = fs::read_to_string(path).expect("Unable to read file");
-    serde_json::from_str(&data).expect("Unable to parse")
+/// `{{name}}`, `{{sdk.version}}` or `{{TM_SDK_DIR2}}`. Names start with a letter so C
+/// initializers such as `{{0}}` are no placeholders.
+const PLACEHOLDER: &str = r"\{\{(?P<name>[A-Za-z_][A-Za-z0-9_]*(?:\.[A-Za-z_][A-Za-z0-9_]*)*)\}\}";
+
+/// Prefix of the environment variables which override context values, `__` separates the
+/// parts of a dotted key: `TMBOOK_CTX_SDK__VERSION` sets `sdk.version`.
+const CONTEXT_ENV_PREFIX: &str = "TMBOOK_CTX_";
+
+/// The values of context.json, nested objects are flattened into dotted keys. Each value
+/// remembers the layer it came from.
+pub struct Context {
+    values: BTreeMap<String, (String, String)>,
 }
 
-fn find_term(mut chapter: String) -> Option<String> {
-    let config = load_config();
-    let object = config.as_object().unwrap();
-    let re = Regex::new(r"(\{\{([a-z_]+)\}\})").unwrap();
-    let str = chapter.clone();
-    let res = re.captures_iter(&str);
-    for cap in res {
-        if object.contains_key(&cap[2]) {
-            let (term, repl) = object.get_key_value(&cap[2]).unwrap();
-            let a = r"(\{\{";
-            let b = r"\}\})";
-            let r = [a, term.as_str(), b].join("");
-            let regex = Regex::new(&r).unwrap();
-            let rstr = repl.as_str().unwrap();
-            chapter = regex.replace_all(chapter.as_str(), rstr).to_string();
-        }
-    }
-    Some(chapter)
+impl Context {
+    /// Loads `context.json` of `root`, then `context.<profile>.json` and the `TMBOOK_CTX_*`
+    /// environment variables on top of it.
+    pub fn load(root: &Path, profile: Option<&str>) -> Result<Context> {
+        let mut context = Context {
+            values: BTreeMap::new(),
+        };
+        context.load_file(root, "context.json")?;
+        if let Some(profile) = profile {
+            context.load_file(root, &format!("context.{}.json", profile))?;
+        }
+        context.load_env();
+        Ok(context)
+    }
+
+    fn load_file(&mut self, root: &Path, name: &str) -> Result<()> {
+        let path = root.join(name);
+        let data = fs::read_to_string(&path)
+            .map_err(|e| Error::msg(format!("Unable to read {}: {}", path.display(), e)))?;
+        let value: Value = serde_json::from_str(&data)
+            .map_err(|e| Error::msg(format!("Unable to parse {}: {}", path.display(), e)))?;
+        if !value.is_object() {
+            return Err(Error::msg(format!(
+                "{} has to contain an object",
+                path.display()
+            )));
+        }
+        let mut errors = Vec::<String>::new();
+        flatten("", &value, name, &mut self.values, &mut errors);
+        if !errors.is_empty() {
+            return Err(Error::msg(format!(
+                "Invalid values in {}:\n{}",
+                path.display(),
+                errors.join("\n")
+            )));
+        }
+        Ok(())
+    }
+
+    fn load_env(&mut self) {
+        let mut vars: Vec<(String, String)> = std::env::vars().collect();
+        vars.sort();
+        for (name, value) in vars {
+            let key = match name.strip_prefix(CONTEXT_ENV_PREFIX) {
+                Some(key) if !key.is_empty() => key.replace("__", "."),
+                _ => continue,
+            };
+            // Environment variables are usually upper case, existing keys keep their case.
+            let key = self
+                .values
+                .keys()
+                .find(|existing| existing.eq_ignore_ascii_case(&key))
+                .cloned()
+                .unwrap_or_else(|| key.to_lowercase());
+            self.values.insert(key, (value, format!("env {}", name)));
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|(value, _)| value.as_str())
+    }
+
+    /// Prints every value with the layer it came from.
+    pub fn show(&self) {
+        for (key, (value, source)) in &self.values {
+            println!("{} = {:?} ({})", key, value, source);
+        }
+    }
 }
 
-impl Preprocessor for ReplacePaths {
-    fn name(&self) -> &str {
-        "replace_paths"
+fn flatten(
+    prefix: &str,
+    value: &Value,
+    source: &str,
+    values: &mut BTreeMap<String, (String, String)>,
+    errors: &mut Vec<String>,
+) {
+    let name_re = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
+    let key = prefix.trim_end_matches('.').to_string();
+    match value {
+        Value::Object(object) => {
+            for (name, value) in object {
+                if !name_re.is_match(name) {
+                    errors.push(format!("  `{}{}`: invalid name", prefix, name));
+                    continue;
+                }
+                flatten(
+                    &format!("{}{}.", prefix, name),
+                    value,
+                    source,
+                    values,
+                    errors,
+                );
+            }
+        }
+        Value::String(text) => {
+            values.insert(key, (text.clone(), source.to_string()));
+        }
+        Value::Number(_) | Value::Bool(_) => {
+            values.insert(key, (value.to_string(), source.to_string()));
+        }
+        Value::Null | Value::Array(_) => {
+            errors.push(format!(
+                "  `{}`: only strings, numbers, booleans and objects are supported",
+                key
+            ));
+        }
+    }
+}
+
+/// The values conditions can test: `renderer`, `env.NAME`, the context and book.toml values
+/// such as `preprocessor.replace_paths.edition`.
+pub struct Variables<'a> {
+    context: &'a Context,
+    config: &'a mdbook::Config,
+    renderer: &'a str,
+}
+
+impl<'a> Variables<'a> {
+    fn get(&self, name: &str) -> Option<String> {
+        if name == "renderer" {
+            return Some(self.renderer.to_string());
+        }
+        if let Some(name) = name.strip_prefix("env.") {
+            return std::env::var(name).ok();
+        }
+        if let Some(value) = self.context.get(name) {
+            return Some(value.to_string());
+        }
+        let value = self.config.get(name)?;
+        match value.as_str() {
+            Some(text) => Some(text.to_string()),
+            None => value
+                .as_integer()
+                .map(|number| number.to_string())
+                .or_else(|| value.as_float().map(|number| number.to_string()))
+                .or_else(|| value.as_bool().map(|flag| flag.to_string())),
+        }
+    }
+}
+
+/// Evaluates `platform == "linux" && !beta || renderer != html`, `&&` binds tighter than `||`.
+/// Names which are not set are empty, an empty, `false` or `0` value is false.
+struct Condition<'a, 'b> {
+    tokens: Vec<&'b str>,
+    position: usize,
+    variables: &'b Variables<'a>,
+    /// Names which are neither set nor environment variables.
+    unknown: Vec<String>,
+}
+
+impl<'a, 'b> Condition<'a, 'b> {
+    fn evaluate(expression: &'b str, variables: &'b Variables<'a>) -> Result<(bool, Vec<String>)> {
+        let re = Regex::new(r#""[^"]*"|==|!=|&&|\|\||!|[A-Za-z0-9_.\-]+|\S"#).unwrap();
+        let mut condition = Condition {
+            tokens: re.find_iter(expression).map(|m| m.as_str()).collect(),
+            position: 0,
+            variables,
+            unknown: Vec::new(),
+        };
+        let value = condition.parse_or()?;
+        if let Some(token) = condition.peek() {
+            return Err(Error::msg(format!("unexpected `{}`", token)));
+        }
+        Ok((value, condition.unknown))
+    }
+
+    fn peek(&self) -> Option<&'b str> {
+        self.tokens.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Option<&'b str> {
+        let token = self.peek();
+        self.position += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<bool> {
+        let mut value = self.parse_and()?;
+        while self.peek() == Some("||") {
+            self.next();
+            // Both sides are parsed to find all errors.
+            value |= self.parse_and()?;
+        }
+        Ok(value)
+    }
+
+    fn parse_and(&mut self) -> Result<bool> {
+        let mut value = self.parse_unary()?;
+        while self.peek() == Some("&&") {
+            self.next();
+            value &= self.parse_unary()?;
+        }
+        Ok(value)
     }
 
-    fn run(&self, _: &PreprocessorContext, mut book: Book) -> Result<Book> {
-        let res = None;
+    fn parse_unary(&mut self) -> Result<bool> {
+        if self.peek() == Some("!") {
+            self.next();
+            return Ok(!self.parse_unary()?);
+        }
+        let name = self.parse_operand()?;
+        let value = self.get(name);
+        match self.peek() {
+            Some("==") | Some("!=") => {
+                let equal = self.next() == Some("==");
+                let operand = self.parse_operand()?;
+                let other = match operand.strip_prefix('"') {
+                    Some(literal) => literal.trim_end_matches('"').to_string(),
+                    None => operand.to_string(),
+                };
+                Ok((value == other) == equal)
+            }
+            _ => Ok(!matches!(value.as_str(), "" | "false" | "0")),
+        }
+    }
 
-        book.for_each_mut(|item: &mut BookItem| {
-            if let Some(Err(_)) = res {
-                return;
+    fn parse_operand(&mut self) -> Result<&'b str> {
+        match self.next() {
+            Some(token) if !matches!(token, "==" | "!=" | "&&" | "||" | "!") => Ok(token),
+            Some(token) => Err(Error::msg(format!("unexpected `{}`", token))),
+            None => Err(Error::msg("unexpected end")),
+        }
+    }
+
+    fn get(&mut self, name: &str) -> String {
+        match self.variables.get(name) {
+            Some(value) => value,
+            None => {
+                if !name.starts_with("env.") && !name.starts_with('"') {
+                    self.unknown.push(name.to_string());
+                }
+                String::new()
             }
+        }
+    }
+}
+
+/// An open `{{#if}}` and whether its content is kept.
+struct Frame {
+    line: usize,
+    parent_active: bool,
+    condition: bool,
+    in_else: bool,
+}
 
-            if let BookItem::Chapter(ref mut chapter) = *item {
-                let content = chapter.content.to_string();
-                chapter.content = find_term(content).unwrap();
+impl Frame {
+    fn is_active(&self) -> bool {
+        self.parent_active && self.condition != self.in_else
+    }
+}
+
+/// Keeps the content of the `{{#if}}` blocks whose condition holds, replaces every known
+/// placeholder in it and warns about the unknown ones, which are kept.
+fn find_term(variables: &Variables, chapter: &Chapter) -> Result<String> {
+    // A tag on its own line is removed with its line.
+    let re = Regex::new(&format!(
+        r"(?m)^[ \t]*\{{\{{(?P<line>#if[^}}]*|else|/if)\}}\}}[ \t]*(?:\r?\n|\z)|\{{\{{(?P<tag>#if[^}}]*|else|/if)\}}\}}|{}",
+        PLACEHOLDER
+    ))
+    .unwrap();
+    let content = chapter.content.as_str();
+    let get_line = |offset: usize| content[..offset].matches('\n').count() + 1;
+    let mut res = String::new();
+    let mut stack = Vec::<Frame>::new();
+    let mut unknown = String::new();
+    let mut last = 0;
+    for cap in re.captures_iter(content) {
+        let m = cap.get(0).unwrap();
+        let active = stack.last().map_or(true, |frame| frame.is_active());
+        if active {
+            res.push_str(&content[last..m.start()]);
+        }
+        last = m.end();
+        let line = get_line(m.start());
+
+        let tag = match cap.name("line").or_else(|| cap.name("tag")) {
+            Some(tag) => tag.as_str().trim(),
+            None => {
+                if active {
+                    match variables.context.get(&cap["name"]) {
+                        Some(value) => res.push_str(value),
+                        None => {
+                            unknown.push_str(&format!("  line {}: `{}`\n", line, m.as_str()));
+                            res.push_str(m.as_str());
+                        }
+                    }
+                }
+                continue;
+            }
+        };
+        if let Some(expression) = tag.strip_prefix("#if") {
+            let (condition, names) = Condition::evaluate(expression, variables).map_err(|e| {
+                Error::msg(format!(
+                    "Invalid condition `{}` in {} line {}: {}",
+                    expression.trim(),
+                    get_chapter_name(chapter),
+                    line,
+                    e
+                ))
+            })?;
+            for name in names {
+                unknown.push_str(&format!("  line {}: `{}` in condition\n", line, name));
             }
-        });
+            stack.push(Frame {
+                line,
+                parent_active: active,
+                condition,
+                in_else: false,
+            });
+        } else {
+            let frame = match stack.last_mut() {
+                Some(frame) if tag == "else" && !frame.in_else => frame,
+                Some(_) if tag == "/if" => {
+                    stack.pop();
+                    continue;
+                }
+                _ => {
+                    return Err(Error::msg(format!(
+                        "Unexpected `{{{{{}}}}}` in {} line {}",
+                        tag,
+                        get_chapter_name(chapter),
+                        line
+                    )))
+                }
+            };
+            frame.in_else = true;
+        }
+    }
+    if let Some(frame) = stack.last() {
+        return Err(Error::msg(format!(
+            "Unclosed `{{{{#if}}}}` in {} line {}",
+            get_chapter_name(chapter),
+            frame.line
+        )));
+    }
+    res.push_str(&content[last..]);
 
-        res.unwrap_or(Ok(())).map(|_| book)
+    warn_chapter(chapter, "Unknown placeholders", &unknown);
+    Ok(res)
+}
+
+impl Preprocessor for ReplacePaths {
+    fn name(&self) -> &str {
+        "replace_paths"
+    }
+
+    fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
+        let context = Context::load(&ctx.root, get_profile().as_deref())?;
+        let variables = Variables {
+            context: &context,
+            config: &ctx.config,
+            renderer: &ctx.renderer,
+        };
+        for_each_chapter_mut(&mut book.sections, &|chapter: &mut Chapter| {
+            chapter.content = find_term(&variables, chapter)?;
+            Ok(())
+        })?;
+
+        Ok(book)
     }
 
     fn supports_renderer(&self, _renderer: &str) -> bool {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_context(values: &[(&str, &str)]) -> Context {
+        Context {
+            values: values
+                .iter()
+                .map(|(key, value)| (key.to_string(), (value.to_string(), String::from("test"))))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn conditions() {
+        let context = get_context(&[
+            ("platform", "linux"),
+            ("beta", "false"),
+            ("count", "0"),
+            ("sdk.version", "2021.11"),
+        ]);
+        let mut config = mdbook::Config::default();
+        config
+            .set("preprocessor.replace_paths.edition", "pro")
+            .unwrap();
+        let variables = Variables {
+            context: &context,
+            config: &config,
+            renderer: "html",
+        };
+        let table = [
+            ("platform", true),
+            ("beta", false),
+            ("count", false),
+            ("!beta", true),
+            ("!!platform", true),
+            ("platform == \"linux\"", true),
+            ("platform == linux", true),
+            ("platform != \"linux\"", false),
+            ("platform != windows", true),
+            ("sdk.version == \"2021.11\"", true),
+            ("renderer == html", true),
+            ("preprocessor.replace_paths.edition == pro", true),
+            // `&&` binds tighter than `||`.
+            ("platform || beta && count", true),
+            ("beta && count || platform", true),
+            ("beta && platform || count", false),
+            ("!platform || !beta", true),
+            ("platform == linux && !beta", true),
+            ("platform == \"windows\" || renderer != html", false),
+        ];
+        for (expression, expected) in table.iter() {
+            let (value, unknown) = Condition::evaluate(expression, &variables).unwrap();
+            assert_eq!(value, *expected, "{}", expression);
+            assert!(unknown.is_empty(), "{}", expression);
+        }
+
+        let (value, unknown) = Condition::evaluate("missing || !other", &variables).unwrap();
+        assert!(value);
+        assert_eq!(unknown, vec!["missing", "other"]);
+
+        for expression in ["", "platform ==", "&& beta", "platform beta", "!"].iter() {
+            assert!(
+                Condition::evaluate(expression, &variables).is_err(),
+                "{:?}",
+                expression
+            );
+        }
+    }
+
+    fn replace(content: &str) -> Result<String> {
+        let context = get_context(&[("platform", "linux"), ("docs", "https://docs/")]);
+        let config = mdbook::Config::default();
+        let variables = Variables {
+            context: &context,
+            config: &config,
+            renderer: "html",
+        };
+        let chapter = Chapter::new("Test", content.to_string(), "test.md", Vec::new());
+        find_term(&variables, &chapter)
+    }
+
+    #[test]
+    fn blocks() {
+        let table = [
+            ("See {{docs}}index.html", "See https://docs/index.html"),
+            ("{{unknown}} and {{0}}", "{{unknown}} and {{0}}"),
+            (
+                "a\n{{#if platform == linux}}\nlinux\n{{else}}\nother\n{{/if}}\nb\n",
+                "a\nlinux\nb\n",
+            ),
+            (
+                "a\n{{#if platform == windows}}\nwindows\n{{else}}\nother {{docs}}\n{{/if}}\nb",
+                "a\nother https://docs/\nb",
+            ),
+            ("x {{#if platform}}on{{else}}off{{/if}} y", "x on y"),
+            (
+                "{{#if platform}}\n{{#if beta}}\nbeta\n{{else}}\nstable\n{{/if}}\n{{/if}}\n",
+                "stable\n",
+            ),
+            (
+                "{{#if beta}}\n{{#if platform}}\nlinux\n{{else}}\nother\n{{/if}}\n{{else}}\nno beta\n{{/if}}\n",
+                "no beta\n",
+            ),
+        ];
+        for (content, expected) in table.iter() {
+            assert_eq!(replace(content).unwrap(), *expected, "{:?}", content);
+        }
+    }
+
+    #[test]
+    fn block_errors() {
+        let table = [
+            (
+                "{{#if platform}}\nopen\n",
+                "Unclosed `{{#if}}` in test.md line 1",
+            ),
+            ("text\n{{/if}}\n", "Unexpected `{{/if}}` in test.md line 2"),
+            ("{{else}}", "Unexpected `{{else}}` in test.md line 1"),
+            (
+                "{{#if platform}}\n{{else}}\n{{else}}\n{{/if}}",
+                "Unexpected `{{else}}` in test.md line 3",
+            ),
+            (
+                "{{#if platform ==}}\n{{/if}}",
+                "Invalid condition `platform ==` in test.md line 1: unexpected end",
+            ),
+        ];
+        for (content, expected) in table.iter() {
+            let error = replace(content).unwrap_err();
+            assert_eq!(error.to_string(), *expected, "{:?}", content);
+        }
+    }
+}
@@ -0,0 +1,695 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use git2::Repository;
+use regex::Regex;
+
+use crate::auto_doc::{Term, TermsFile};
+use crate::utility::get_head_commit;
+
+/// `ret (*name)(args)`, the name of a function pointer.
+const FN_POINTER: &str = r"\(\s*\*\s*([A-Za-z_][A-Za-z0-9_]*)\s*\)\s*\(";
+/// The text before the `{` of a struct, union or enum definition.
+const TYPE_BLOCK: &str = r"\b(struct|union|enum)\s*([A-Za-z_][A-Za-z0-9_]*)?\s*$";
+
+/// A declaration found in a header, `term` is the text used in the book to refer to it.
+struct Declaration {
+    term: String,
+    name: String,
+    /// Byte offset of the declaration in the header, its doc comment is right above it.
+    offset: usize,
+    signature: String,
+}
+
+/// A top level piece of a header: either a statement terminated by `;` or a block with its
+/// header (text before `{`), body and trailer (text between `}` and `;`).
+enum Item {
+    Statement {
+        offset: usize,
+        text: String,
+    },
+    Block {
+        offset: usize,
+        header: String,
+        body: String,
+        body_offset: usize,
+        trailer: String,
+    },
+}
+
+/// Replaces a piece of source with spaces, so offsets in the result match the original.
+fn blank(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\n' => String::from("\n"),
+            _ => " ".repeat(c.len_utf8()),
+        })
+        .collect()
+}
+
+/// Blanks all comments.
+fn strip_comments(source: &str) -> String {
+    let re = Regex::new(r"(?s)/\*.*?\*/|//[^\n]*").unwrap();
+    re.replace_all(source, |caps: &regex::Captures| blank(&caps[0]))
+        .to_string()
+}
+
+/// Splits the header into preprocessor directives with their offset and the remaining code,
+/// in which the directives are blanked.
+fn split_preprocessor(source: &str) -> (Vec<(usize, String)>, String) {
+    let mut directives = Vec::<(usize, String)>::new();
+    let mut code = String::new();
+    let mut current: Option<(usize, String)> = None;
+    let mut offset = 0;
+    for line in source.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+        let continued = line.trim_end().ends_with('\\');
+        let text = line.trim_end().trim_end_matches('\\');
+        match current.as_mut() {
+            Some((_, directive)) => directive.push_str(text),
+            None if line.trim_start().starts_with('#') => {
+                let indent = line.len() - line.trim_start().len();
+                current = Some((start + indent, text.trim_start().to_string()));
+            }
+            None => {
+                code.push_str(line);
+                continue;
+            }
+        }
+        code.push_str(&blank(line));
+        if !continued {
+            directives.push(current.take().unwrap());
+        }
+    }
+    if let Some(directive) = current {
+        directives.push(directive);
+    }
+    (directives, code)
+}
+
+/// Splits `code`, which starts at `base` in the header, into statements and blocks.
+fn parse_items(code: &str, base: usize) -> Vec<Item> {
+    let type_block = Regex::new(TYPE_BLOCK).unwrap();
+    let mut items = Vec::<Item>::new();
+    let mut buffer = String::new();
+    let mut start: Option<usize> = None;
+    let mut chars = code.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            ';' => {
+                items.push(Item::Statement {
+                    offset: base + start.take().unwrap_or(i),
+                    text: buffer.trim().to_string(),
+                });
+                buffer.clear();
+            }
+            '{' => {
+                let body_offset = i + 1;
+                let mut body_end = code.len();
+                let mut depth = 1;
+                for (j, c) in chars.by_ref() {
+                    match c {
+                        '{' => depth += 1,
+                        '}' => depth -= 1,
+                        _ => {}
+                    }
+                    if depth == 0 {
+                        body_end = j;
+                        break;
+                    }
+                }
+                let body = &code[body_offset..body_end];
+                let header = buffer.trim().to_string();
+                let offset = base + start.take().unwrap_or(i);
+                buffer.clear();
+                // `extern "C" { ... }` only wraps other declarations.
+                if header.starts_with("extern") {
+                    items.extend(parse_items(body, base + body_offset));
+                    continue;
+                }
+                // The declarators up to the `;` belong to a type, e.g. `} tm_foo_t;`. Function
+                // definitions have no trailer.
+                let mut trailer = String::new();
+                if type_block.is_match(&header) {
+                    for (_, c) in chars.by_ref() {
+                        if c == ';' {
+                            break;
+                        }
+                        trailer.push(c);
+                    }
+                }
+                items.push(Item::Block {
+                    offset,
+                    header,
+                    body: body.to_string(),
+                    body_offset: base + body_offset,
+                    trailer: trailer.trim().to_string(),
+                });
+            }
+            _ => {
+                if start.is_none() && !c.is_whitespace() {
+                    start = Some(i);
+                }
+                buffer.push(c);
+            }
+        }
+    }
+    items
+}
+
+fn is_term(name: &str) -> bool {
+    name.starts_with("tm_") || name.starts_with("TM_")
+}
+
+fn get_identifiers(source: &str) -> Vec<String> {
+    let re = Regex::new(r"[A-Za-z_][A-Za-z0-9_]*").unwrap();
+    re.find_iter(source)
+        .map(|m| m.as_str().to_string())
+        .collect()
+}
+
+/// Collapses all whitespace of a declaration into single spaces.
+fn get_signature(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn add(res: &mut Vec<Declaration>, term: String, name: String, offset: usize, signature: String) {
+    if is_term(&name) {
+        res.push(Declaration {
+            term,
+            name,
+            offset,
+            signature,
+        });
+    }
+}
+
+fn parse_macro(offset: usize, directive: &str, res: &mut Vec<Declaration>) {
+    let re = Regex::new(r"^\s*#\s*define\s+([A-Za-z_][A-Za-z0-9_]*)(\([^)]*\))?").unwrap();
+    if let Some(cap) = re.captures(directive) {
+        let name = cap[1].to_string();
+        // The body of a function-like macro is an implementation detail.
+        let (term, signature) = if cap.get(2).is_some() {
+            (format!("{}()", name), get_signature(&cap[0]))
+        } else {
+            (name.clone(), get_signature(directive))
+        };
+        add(res, term, name, offset, signature);
+    }
+}
+
+/// Parses a statement such as a function declaration, a typedef or a variable.
+fn parse_statement(offset: usize, statement: &str, res: &mut Vec<Declaration>) {
+    let fn_pointer = Regex::new(FN_POINTER).unwrap();
+    let function = Regex::new(r"([A-Za-z_][A-Za-z0-9_]*)\s*\(").unwrap();
+    let signature = get_signature(statement);
+    if let Some(cap) = fn_pointer.captures(statement) {
+        let name = cap[1].to_string();
+        add(res, format!("{}()", name), name, offset, signature);
+    } else if let Some(cap) = function.captures(statement) {
+        let name = cap[1].to_string();
+        add(res, format!("{}()", name), name, offset, signature);
+    } else {
+        // The declared name is the last identifier, ignoring array sizes.
+        let declarator = statement.split('[').next().unwrap_or("");
+        if let Some(name) = get_identifiers(declarator).pop() {
+            add(res, name.clone(), name, offset, signature);
+        }
+    }
+}
+
+/// Members of API and interface structs get their own term: `tm_foo_api.bar()`.
+fn parse_members(parent: &str, body: &str, body_offset: usize, res: &mut Vec<Declaration>) {
+    let fn_pointer = Regex::new(FN_POINTER).unwrap();
+    for item in parse_items(body, body_offset) {
+        let (offset, statement) = match item {
+            Item::Statement { offset, text } => (offset, text),
+            Item::Block {
+                offset, trailer, ..
+            } => (offset, trailer),
+        };
+        let signature = get_signature(&statement);
+        // Members are not prefixed with `tm_`, so they are always added.
+        if let Some(cap) = fn_pointer.captures(&statement) {
+            let name = format!("{}.{}", parent, &cap[1]);
+            res.push(Declaration {
+                term: format!("{}()", name),
+                name,
+                offset,
+                signature,
+            });
+        } else if let Some(member) =
+            get_identifiers(statement.split('[').next().unwrap_or("")).pop()
+        {
+            let name = format!("{}.{}", parent, member);
+            res.push(Declaration {
+                term: name.clone(),
+                name,
+                offset,
+                signature,
+            });
+        }
+    }
+}
+
+fn parse_enumerators(body: &str, body_offset: usize, res: &mut Vec<Declaration>) {
+    let re = Regex::new(r"[A-Za-z_][A-Za-z0-9_]*").unwrap();
+    let mut start = 0;
+    for enumerator in body.split(',') {
+        if let Some(m) = re.find(enumerator) {
+            let name = m.as_str().to_string();
+            let offset = body_offset + start + m.start();
+            add(res, name.clone(), name, offset, get_signature(enumerator));
+        }
+        start += enumerator.len() + 1;
+    }
+}
+
+fn parse_block(
+    offset: usize,
+    header: &str,
+    body: &str,
+    body_offset: usize,
+    trailer: &str,
+    res: &mut Vec<Declaration>,
+) {
+    let re = Regex::new(TYPE_BLOCK).unwrap();
+    match re.captures(header) {
+        Some(cap) => {
+            let mut names = Vec::<String>::new();
+            if let Some(name) = cap.get(2) {
+                names.push(name.as_str().to_string());
+            }
+            for name in get_identifiers(trailer) {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+            for name in &names {
+                let signature = format!("{} {}", &cap[1], name);
+                add(res, name.clone(), name.clone(), offset, signature);
+            }
+            if &cap[1] == "enum" {
+                parse_enumerators(body, body_offset, res);
+            } else if let Some(name) = names.iter().find(|name| is_term(name)) {
+                if name.ends_with("_api") || name.ends_with("_i") {
+                    parse_members(name, body, body_offset, res);
+                }
+            }
+        }
+        // A function definition, e.g. an inline helper.
+        None => parse_statement(offset, header, res),
+    }
+}
+
+/// The first sentence of the comment right above `offset`, which has to start on its own line.
+fn get_summary(source: &str, offset: usize) -> Option<String> {
+    let before = source[..offset].trim_end();
+    if source[before.len()..offset].matches('\n').count() > 1 {
+        return None;
+    }
+    let start = if before.ends_with("*/") {
+        before.rfind("/*")?
+    } else {
+        // The first of the consecutive `//` lines.
+        let mut start = None;
+        let mut end = before.len();
+        for line in before.rsplit('\n') {
+            if !line.trim_start().starts_with("//") {
+                break;
+            }
+            let line_start = end - line.len();
+            start = Some(line_start + line.len() - line.trim_start().len());
+            end = line_start.saturating_sub(1);
+        }
+        start?
+    };
+    let line_start = before[..start].rfind('\n').map_or(0, |i| i + 1);
+    if !before[line_start..start].trim().is_empty() {
+        return None;
+    }
+
+    let markers = Regex::new(r"(?m)^\s*(///?|/\*\*?|\*/|\*)|\*/").unwrap();
+    let text = get_signature(&markers.replace_all(&before[start..], " "));
+    let end = text.find(". ").map_or(text.len(), |i| i + 1);
+    let summary = text[..end].to_string();
+    if summary.is_empty() {
+        None
+    } else {
+        Some(summary)
+    }
+}
+
+/// Declarations of a header as `(term, name, signature, summary)`.
+fn parse_header(source: &str) -> Vec<(String, String, String, Option<String>)> {
+    let code = strip_comments(source);
+    let (directives, code) = split_preprocessor(&code);
+    let mut res = Vec::<Declaration>::new();
+    for (offset, directive) in &directives {
+        parse_macro(*offset, directive, &mut res);
+    }
+    for item in parse_items(&code, 0) {
+        match item {
+            Item::Statement { offset, text } => parse_statement(offset, &text, &mut res),
+            Item::Block {
+                offset,
+                header,
+                body,
+                body_offset,
+                trailer,
+            } => parse_block(offset, &header, &body, body_offset, &trailer, &mut res),
+        }
+    }
+    res.into_iter()
+        .map(|d| {
+            let summary = get_summary(source, d.offset);
+            (d.term, d.name, d.signature, summary)
+        })
+        .collect()
+}
+
+fn find_headers(dir: &Path, res: &mut Vec<PathBuf>) -> io::Result<()> {
+    let entries = fs::read_dir(dir)
+        .map_err(|e| io::Error::new(e.kind(), format!("Cannot read {}: {}", dir.display(), e)))?;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            find_headers(&path, res)?;
+        } else if path.extension().map_or(false, |ext| ext == "h") {
+            res.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Scans every header below `headers` and returns the terms sorted by name. The doc path of
+/// a term is the header path relative to `headers`, its anchor the declared name. Fails if
+/// `headers` cannot be read or contains no headers, e.g. because of a wrong path.
+pub fn generate(headers: &Path) -> io::Result<Vec<Term>> {
+    let mut files = Vec::<PathBuf>::new();
+    find_headers(headers, &mut files)?;
+    if files.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No headers found in {}", headers.display()),
+        ));
+    }
+    files.sort();
+
+    let mut terms = BTreeMap::<String, Term>::new();
+    for file in files {
+        let source = match fs::read_to_string(&file) {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("Warning: Cannot read {:?}: {}", file, e);
+                continue;
+            }
+        };
+        let relative = file.strip_prefix(headers).unwrap_or(&file);
+        let path = relative.to_string_lossy().replace('\\', "/");
+        let file_name = relative
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        for (term, name, signature, summary) in parse_header(&source) {
+            // The first declaration wins, later ones are usually forward declarations.
+            terms.entry(term.clone()).or_insert(Term {
+                term,
+                path: path.clone(),
+                file: file_name.clone(),
+                name,
+                id: serde_json::Number::from(0),
+                signature: Some(signature),
+                summary,
+            });
+        }
+    }
+
+    Ok(terms
+        .into_iter()
+        .enumerate()
+        .map(|(id, (_, mut term))| {
+            term.id = serde_json::Number::from(id);
+            term
+        })
+        .collect())
+}
+
+/// Generates the terms of `headers`, writes them to `output` and prints which terms were
+/// added or removed compared to the previous file. The file records the SDK `version` and the
+/// commit of the headers.
+pub fn generate_terms(headers: &Path, output: &Path, version: Option<&str>) -> io::Result<()> {
+    let file = TermsFile {
+        version: version.map(String::from),
+        revision: Repository::discover(headers)
+            .ok()
+            .and_then(|repo| get_head_commit(&repo)),
+        terms: generate(headers)?,
+    };
+    let old = fs::read_to_string(output)
+        .ok()
+        .and_then(|data| TermsFile::parse(&data).ok())
+        .unwrap_or_default();
+    let terms = &file.terms;
+    let old: BTreeMap<&str, &Term> = old.terms.iter().map(|t| (t.term.as_str(), t)).collect();
+    let new: BTreeMap<&str, &Term> = terms.iter().map(|t| (t.term.as_str(), t)).collect();
+
+    let added: Vec<_> = new.keys().filter(|t| !old.contains_key(*t)).collect();
+    let removed: Vec<_> = old.keys().filter(|t| !new.contains_key(*t)).collect();
+    let moved: Vec<_> = new
+        .iter()
+        .filter(|(t, term)| {
+            old.get(*t)
+                .map_or(false, |o| o.path != term.path || o.name != term.name)
+        })
+        .map(|(t, _)| t)
+        .collect();
+    for term in &added {
+        println!("+ {}", term);
+    }
+    for term in &removed {
+        println!("- {}", term);
+    }
+    for term in &moved {
+        println!("~ {}", term);
+    }
+    println!(
+        "{} terms: {} added, {} removed, {} moved",
+        terms.len(),
+        added.len(),
+        removed.len(),
+        moved.len()
+    );
+
+    let mut data = serde_json::to_string_pretty(&file)?;
+    data.push('\n');
+    fs::write(output, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_names(source: &str) -> Vec<(String, String)> {
+        parse_header(source)
+            .into_iter()
+            .map(|(term, name, _, _)| (term, name))
+            .collect()
+    }
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected
+            .iter()
+            .map(|(term, name)| (term.to_string(), name.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn items() {
+        let items = parse_items("int a; struct s { int b; } c; void f(void) { g(); }", 0);
+        assert_eq!(items.len(), 3);
+        match &items[1] {
+            Item::Block {
+                offset,
+                header,
+                body,
+                trailer,
+                ..
+            } => {
+                assert_eq!(*offset, 7);
+                assert_eq!(header, "struct s");
+                assert_eq!(body.trim(), "int b;");
+                assert_eq!(trailer, "c");
+            }
+            Item::Statement { .. } => panic!("expected a block"),
+        }
+        // A function definition has no trailer, the next statement stays separate.
+        match &items[2] {
+            Item::Block {
+                header, trailer, ..
+            } => {
+                assert_eq!(header, "void f(void)");
+                assert_eq!(trailer, "");
+            }
+            Item::Statement { .. } => panic!("expected a block"),
+        }
+    }
+
+    #[test]
+    fn declarations() {
+        let source = r#"
+#pragma once
+#define TM_FOO_VERSION 3
+#define TM_STRHASH(s) \
+    tm_murmur_hash_string(s)
+#define helper_macro 1
+
+typedef struct tm_allocator_i tm_allocator_i;
+extern "C" {
+void tm_foo_init(tm_allocator_i *a);
+}
+typedef struct tm_vec2_t
+{
+    float x, y;
+} tm_vec2_t;
+enum tm_color {
+    TM_COLOR_RED = 1,
+    TM_COLOR_GREEN,
+};
+static inline float tm_dot(tm_vec2_t a, tm_vec2_t b) { return a.x * b.x + a.y * b.y; }
+extern const char *tm_names[TM_FOO_VERSION];
+"#;
+        assert_eq!(
+            get_names(source),
+            pairs(&[
+                ("TM_FOO_VERSION", "TM_FOO_VERSION"),
+                ("TM_STRHASH()", "TM_STRHASH"),
+                ("tm_allocator_i", "tm_allocator_i"),
+                ("tm_foo_init()", "tm_foo_init"),
+                ("tm_vec2_t", "tm_vec2_t"),
+                ("tm_color", "tm_color"),
+                ("TM_COLOR_RED", "TM_COLOR_RED"),
+                ("TM_COLOR_GREEN", "TM_COLOR_GREEN"),
+                ("tm_dot()", "tm_dot"),
+                ("tm_names", "tm_names"),
+            ])
+        );
+    }
+
+    #[test]
+    fn members() {
+        let source = r#"
+struct tm_foo_api
+{
+    // Creates a foo.
+    struct tm_foo_o *(*create)(tm_allocator_i *a);
+    void (*destroy)(struct tm_foo_o *foo);
+    uint32_t version;
+    struct {
+        float x;
+    } size;
+};
+struct tm_bar_t
+{
+    void (*callback)(void);
+};
+"#;
+        assert_eq!(
+            get_names(source),
+            pairs(&[
+                ("tm_foo_api", "tm_foo_api"),
+                ("tm_foo_api.create()", "tm_foo_api.create"),
+                ("tm_foo_api.destroy()", "tm_foo_api.destroy"),
+                ("tm_foo_api.version", "tm_foo_api.version"),
+                ("tm_foo_api.size", "tm_foo_api.size"),
+                ("tm_bar_t", "tm_bar_t"),
+            ])
+        );
+    }
+
+    #[test]
+    fn summaries() {
+        let source = r#"
+// Allocates memory. Returns NULL on failure.
+void *tm_alloc(uint64_t size);
+
+/* Frees memory. */
+void tm_free(void *p);
+
+// Not directly above.
+
+void tm_other(void);
+int x; // Trailing comment.
+void tm_last(void);
+"#;
+        let summaries: Vec<(String, Option<String>)> = parse_header(source)
+            .into_iter()
+            .map(|(term, _, _, summary)| (term, summary))
+            .collect();
+        assert_eq!(
+            summaries,
+            vec![
+                (
+                    String::from("tm_alloc()"),
+                    Some(String::from("Allocates memory."))
+                ),
+                (
+                    String::from("tm_free()"),
+                    Some(String::from("Frees memory."))
+                ),
+                (String::from("tm_other()"), None),
+                (String::from("tm_last()"), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn signatures() {
+        let source =
+            "#define TM_MAX(a, b) ((a) > (b) ? (a) : (b))\nvoid  tm_foo(int a,\n    int b);\n";
+        let signatures: Vec<String> = parse_header(source)
+            .into_iter()
+            .map(|(_, _, signature, _)| signature)
+            .collect();
+        assert_eq!(
+            signatures,
+            vec!["#define TM_MAX(a, b)", "void tm_foo(int a, int b)"]
+        );
+    }
+
+    #[test]
+    fn terms_file() {
+        let term = r#"{"term": "tm_foo_t", "path": "foo.h", "file": "foo.h", "name": "tm_foo_t", "id": 0}"#;
+        let list = TermsFile::parse(&format!("[{}]", term)).unwrap();
+        assert_eq!(list.version, None);
+        assert_eq!(list.terms.len(), 1);
+        let file =
+            TermsFile::parse(&format!(r#"{{"version": "2021.10", "terms": [{}]}}"#, term)).unwrap();
+        assert_eq!(file.version.as_deref(), Some("2021.10"));
+        assert_eq!(file.terms[0].name, "tm_foo_t");
+    }
+
+    #[test]
+    fn missing_headers() {
+        let dir = std::env::temp_dir().join(format!("tmbook-terms-{}", std::process::id()));
+        let output = dir.join("terms.json");
+        let _ = fs::remove_dir_all(&dir);
+        assert!(generate(&dir.join("missing")).is_err());
+
+        fs::create_dir_all(dir.join("headers")).unwrap();
+        fs::write(dir.join("headers/readme.md"), "No headers").unwrap();
+        fs::write(&output, "[]").unwrap();
+        assert!(generate_terms(&dir.join("headers"), &output, None).is_err());
+        // The previous terms are kept.
+        assert_eq!(fs::read_to_string(&output).unwrap(), "[]");
+
+        fs::write(dir.join("headers/foo.h"), "struct tm_foo_t;").unwrap();
+        let terms = generate(&dir.join("headers")).unwrap();
+        assert_eq!(terms.len(), 1);
+        assert_eq!(terms[0].path, "foo.h");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
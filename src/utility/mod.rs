@@ -1,7 +1,11 @@
 use flate2::read::GzDecoder;
 use git2::Repository;
+use mdbook::book::{Book, BookItem, Chapter};
+use rayon::prelude::*;
 use reqwest::*;
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
     fs::{self, ReadDir},
     io::{self, Cursor},
     path::{Path, PathBuf},
@@ -12,6 +16,21 @@ pub const TM_BOOK_CODE_SNIPPETS: &str =
     "https://github.com/OurMachinery/themachinery-book-code-snippets";
 pub const TM_BOOKS_REPO: &str = "https://github.com/OurMachinery/themachinery-books";
 const TM_BOOK_BIN_DIR: &str = "./mdbook-bin";
+pub const TM_BOOK_SNIPPET_SOURCES_DIR: &str = "./snippet_sources";
+const TM_BOOK_SNIPPET_SOURCE_ENV_PREFIX: &str = "TM_BOOK_SNIPPETS_";
+const TM_BOOK_PROFILE_ENV: &str = "TMBOOK_PROFILE";
+pub const TM_BOOK_LOCK_FILE: &str = "./tmbook.lock";
+const TM_BOOK_CODE_SNIPPETS_LOCK_KEY: &str = "code_snippets";
+const TM_BOOK_CODE_SNIPPETS_BRANCH: &str = "master";
+
+/// A code snippet source as declared in the `[snippet_sources]` table of the `book.toml`.
+/// Either a local `path` or a `git` url which is pinned to `rev` (branch, tag or commit).
+#[derive(Deserialize, Debug)]
+pub struct SnippetSource {
+    path: Option<String>,
+    git: Option<String>,
+    rev: Option<String>,
+}
 
 pub fn get_mdbook_url() -> &'static str {
     if cfg!(windows) {
@@ -63,7 +82,136 @@ pub fn get_clang_format(path: Option<&str>) -> PathBuf {
     }
 }
 
-pub fn check_and_set_or_download_book_code_snippets(code_snippets_path: &Path) {
+/// Commits the snippet repositories are pinned to, stored in the `tmbook.lock` next to the `book.toml`.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct LockFile {
+    #[serde(default)]
+    pub snippets: BTreeMap<String, String>,
+}
+
+impl LockFile {
+    pub fn load(path: &Path) -> LockFile {
+        match fs::read_to_string(path) {
+            Ok(data) => match toml::from_str(&data) {
+                Ok(lock) => lock,
+                Err(e) => {
+                    eprintln!("Warning: Cannot parse {:?}: {}", path, e);
+                    LockFile::default()
+                }
+            },
+            Err(_) => LockFile::default(),
+        }
+    }
+
+    pub fn save(&self, path: &Path) {
+        let data = toml::to_string_pretty(self).unwrap();
+        if let Err(e) = fs::write(path, data) {
+            eprintln!("Warning: Cannot write {:?}: {}", path, e);
+        }
+    }
+}
+
+pub fn get_head_commit(repo: &Repository) -> Option<String> {
+    let head = repo.head().ok()?;
+    let commit = head.peel_to_commit().ok()?;
+    Some(commit.id().to_string())
+}
+
+fn fetch_origin(repo: &Repository) -> std::result::Result<(), git2::Error> {
+    let mut remote = repo.find_remote("origin")?;
+    remote.fetch(&[] as &[&str], None, None)
+}
+
+fn checkout_object(
+    repo: &Repository,
+    object: &git2::Object,
+) -> std::result::Result<(), git2::Error> {
+    let commit = object.peel_to_commit()?;
+    repo.checkout_tree(
+        commit.as_object(),
+        Some(git2::build::CheckoutBuilder::new().force()),
+    )?;
+    repo.set_head_detached(commit.id())
+}
+
+fn checkout_revision(repo: &Repository, rev: &str) -> std::result::Result<(), git2::Error> {
+    // Prefer the remote branch so a branch name always follows the latest fetched state.
+    let object = repo
+        .revparse_single(&format!("origin/{}", rev))
+        .or_else(|_| repo.revparse_single(rev))?;
+    checkout_object(repo, &object)
+}
+
+fn checkout_locked_commit(repo: &Repository, commit: &str) -> std::result::Result<(), git2::Error> {
+    let object = match repo.revparse_single(commit) {
+        Ok(object) => object,
+        Err(_) => {
+            fetch_origin(repo)?;
+            repo.revparse_single(commit)?
+        }
+    };
+    checkout_object(repo, &object)
+}
+
+/// Clones or updates the repository at `path` and checks out either the `locked` commit or
+/// the latest state of `rev`. Returns the commit which is checked out afterwards.
+fn update_git_snippet_source(
+    url: &str,
+    rev: &str,
+    path: &Path,
+    locked: Option<&String>,
+) -> std::result::Result<String, git2::Error> {
+    let repo = if path.exists() {
+        Repository::open(path)?
+    } else {
+        eprintln!("Download {} ...", url);
+        Repository::clone(url, path)?
+    };
+    match locked {
+        Some(commit) => checkout_locked_commit(&repo, commit)?,
+        None => {
+            if let Err(e) = fetch_origin(&repo) {
+                eprintln!(
+                    "Warning: Cannot update {}, using the local checkout: {}",
+                    url,
+                    e.message()
+                );
+            }
+            checkout_revision(&repo, rev)?
+        }
+    }
+    Ok(get_head_commit(&repo).unwrap_or_default())
+}
+
+/// Checks out the commit `tmbook.lock` pins a fresh clone of the code snippet repository to,
+/// or records its commit if the lock has no entry yet. Existing checkouts are left alone,
+/// `check_snippets_lock` warns if they differ from the lock.
+fn pin_book_code_snippets(repo: &Repository, lock_path: &Path) {
+    let mut lock = LockFile::load(lock_path);
+    match lock.snippets.get(TM_BOOK_CODE_SNIPPETS_LOCK_KEY) {
+        Some(commit) => {
+            if get_head_commit(repo).as_ref() == Some(commit) {
+                return;
+            }
+            if let Err(e) = checkout_locked_commit(repo, commit) {
+                eprintln!(
+                    "Warning: Cannot checkout the locked commit {}: {}",
+                    commit,
+                    e.message()
+                );
+            }
+        }
+        None => {
+            if let Some(commit) = get_head_commit(repo) {
+                lock.snippets
+                    .insert(TM_BOOK_CODE_SNIPPETS_LOCK_KEY.to_string(), commit);
+                lock.save(lock_path);
+            }
+        }
+    }
+}
+
+pub fn check_and_set_or_download_book_code_snippets(code_snippets_path: &Path, lock_path: &Path) {
     let path = std::env::var("TM_BOOK_CODE_SNIPPETS");
     if path.is_err() {
         println!(
@@ -88,7 +236,8 @@ pub fn check_and_set_or_download_book_code_snippets(code_snippets_path: &Path) {
         } else {
             let url = TM_BOOK_CODE_SNIPPETS;
             match Repository::clone(url, code_snippets_path) {
-                Ok(_) => {
+                Ok(repo) => {
+                    pin_book_code_snippets(&repo, lock_path);
                     std::env::set_var(
                         "TM_BOOK_CODE_SNIPPETS",
                         std::fs::canonicalize(&code_snippets_path)
@@ -112,6 +261,267 @@ pub fn check_and_set_or_download_book_code_snippets(code_snippets_path: &Path) {
     }
 }
 
+/// Returns the name of the env. variable which holds the root of the snippet source `alias`.
+pub fn get_snippet_source_env(alias: &str) -> String {
+    format!(
+        "{}{}",
+        TM_BOOK_SNIPPET_SOURCE_ENV_PREFIX,
+        alias.to_uppercase().replace('-', "_")
+    )
+}
+
+pub fn load_snippet_sources(book_toml: &Path) -> BTreeMap<String, SnippetSource> {
+    let mut res = BTreeMap::<String, SnippetSource>::new();
+    let data = match fs::read_to_string(book_toml) {
+        Ok(data) => data,
+        Err(_) => return res,
+    };
+    let config: toml::Value = match toml::from_str(&data) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Warning: Cannot parse {:?}: {}", book_toml, e);
+            return res;
+        }
+    };
+    if let Some(sources) = config.get("snippet_sources").and_then(|s| s.as_table()) {
+        for (alias, source) in sources {
+            match source.clone().try_into::<SnippetSource>() {
+                Ok(source) => {
+                    res.insert(alias.clone(), source);
+                }
+                Err(e) => eprintln!("Warning: Invalid snippet source `{}`: {}", alias, e),
+            }
+        }
+    }
+    res
+}
+
+/// Makes every source of the `[snippet_sources]` table available to `insert_code(@alias/...)`.
+/// Git sources are cloned or updated into `cache_dir` and pinned to the commit in the lock file.
+pub fn check_and_set_snippet_sources(book_toml: &Path, cache_dir: &Path, lock_path: &Path) {
+    let sources = load_snippet_sources(book_toml);
+    let book_root = book_toml.parent().unwrap_or_else(|| Path::new("."));
+    let mut lock = LockFile::load(lock_path);
+    let mut lock_changed = false;
+    for (alias, source) in sources {
+        let env = get_snippet_source_env(&alias);
+        if std::env::var(&env).is_ok() {
+            continue;
+        }
+        let path = if let Some(path) = &source.path {
+            book_root.join(path)
+        } else if let Some(url) = &source.git {
+            let path = cache_dir.join(&alias);
+            let rev = source.rev.as_deref().unwrap_or("master");
+            match update_git_snippet_source(url, rev, &path, lock.snippets.get(&alias)) {
+                Ok(commit) => {
+                    if !lock.snippets.contains_key(&alias) {
+                        lock.snippets.insert(alias.clone(), commit);
+                        lock_changed = true;
+                    }
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Cannot checkout `{}` of {} for snippet source `{}`: {}",
+                        rev,
+                        url,
+                        alias,
+                        e.message()
+                    );
+                    continue;
+                }
+            }
+            path
+        } else {
+            eprintln!(
+                "Warning: Snippet source `{}` needs either a `path` or a `git` url",
+                alias
+            );
+            continue;
+        };
+        match std::fs::canonicalize(&path) {
+            Ok(path) => std::env::set_var(&env, path.as_os_str()),
+            Err(_) => eprintln!(
+                "Warning: Snippet source `{}`: Path: {:?} does not exist!",
+                alias, path
+            ),
+        }
+    }
+    if lock_changed {
+        lock.save(lock_path);
+    }
+}
+
+/// Fetches the code snippet repository and all git snippet sources, checks out their
+/// latest revision and records the new commits in the lock file.
+pub fn update_snippets(
+    code_snippets_path: &Path,
+    book_toml: &Path,
+    cache_dir: &Path,
+    lock_path: &Path,
+) {
+    let mut lock = LockFile::load(lock_path);
+    let mut updated = BTreeMap::<String, String>::new();
+
+    match update_git_snippet_source(
+        TM_BOOK_CODE_SNIPPETS,
+        TM_BOOK_CODE_SNIPPETS_BRANCH,
+        code_snippets_path,
+        None,
+    ) {
+        Ok(commit) => {
+            updated.insert(TM_BOOK_CODE_SNIPPETS_LOCK_KEY.to_string(), commit);
+        }
+        Err(e) => eprintln!("Cannot update {}: {}", TM_BOOK_CODE_SNIPPETS, e.message()),
+    }
+
+    for (alias, source) in load_snippet_sources(book_toml) {
+        if let Some(url) = &source.git {
+            let rev = source.rev.as_deref().unwrap_or("master");
+            match update_git_snippet_source(url, rev, &cache_dir.join(&alias), None) {
+                Ok(commit) => {
+                    updated.insert(alias, commit);
+                }
+                Err(e) => eprintln!("Cannot update {}: {}", url, e.message()),
+            }
+        }
+    }
+
+    for (name, commit) in updated {
+        match lock.snippets.insert(name.clone(), commit.clone()) {
+            Some(old) if old == commit => println!("{}: {} (unchanged)", name, commit),
+            Some(old) => println!("{}: {} -> {}", name, old, commit),
+            None => println!("{}: {}", name, commit),
+        }
+    }
+    lock.save(lock_path);
+}
+
+fn check_snippet_repository_lock(name: &str, path: &Path, locked: &str) {
+    let repo = match Repository::discover(path) {
+        Ok(repo) => repo,
+        Err(_) => return,
+    };
+    if let Some(commit) = get_head_commit(&repo) {
+        if commit != locked {
+            eprintln!(
+                "Warning: Snippet repository `{}` at {:?} is at {} but tmbook.lock expects {}. \
+                 Run `git checkout {}` there to sync it or `tmbook snippets update` to bump the lock.",
+                name, path, commit, locked, locked
+            );
+        }
+    }
+}
+
+/// Warns about every snippet repository whose checkout differs from the commit in the lock file.
+pub fn check_snippets_lock(lock_path: &Path) {
+    let lock = LockFile::load(lock_path);
+    for (name, locked) in &lock.snippets {
+        let env = if name == TM_BOOK_CODE_SNIPPETS_LOCK_KEY {
+            String::from("TM_BOOK_CODE_SNIPPETS")
+        } else {
+            get_snippet_source_env(name)
+        };
+        if let Ok(path) = std::env::var(env) {
+            check_snippet_repository_lock(name, Path::new(&path), locked);
+        }
+    }
+}
+
+/// Returns the number of worker threads used to process chapters, `0` means one per cpu.
+pub fn get_jobs(jobs: Option<&str>) -> usize {
+    let jobs = match jobs {
+        Some(jobs) => Some(jobs.to_string()),
+        None => std::env::var("TM_BOOK_JOBS").ok(),
+    };
+    match jobs {
+        Some(jobs) => jobs.parse::<usize>().unwrap_or_else(|_| {
+            eprintln!("Warning: Invalid number of jobs: `{}`", jobs);
+            0
+        }),
+        None => 0,
+    }
+}
+
+/// Selects the context profile of replace_path, `--profile` wins over `TMBOOK_PROFILE`. The
+/// choice is stored in the environment, so the preprocessors started by mdbook see it too.
+pub fn set_profile(profile: Option<&str>) {
+    if let Some(profile) = profile {
+        std::env::set_var(TM_BOOK_PROFILE_ENV, profile);
+    }
+}
+
+pub fn get_profile() -> Option<String> {
+    std::env::var(TM_BOOK_PROFILE_ENV)
+        .ok()
+        .filter(|profile| !profile.is_empty())
+}
+
+/// Calls `f` for every chapter of `items` (including the nested ones) on the worker pool.
+/// Every chapter is only touched by one call, so the result does not depend on the scheduling.
+pub fn for_each_chapter_mut<F>(items: &mut [BookItem], f: &F) -> mdbook::errors::Result<()>
+where
+    F: Fn(&mut Chapter) -> mdbook::errors::Result<()> + Sync,
+{
+    items.par_iter_mut().try_for_each(|item| {
+        if let BookItem::Chapter(ref mut chapter) = *item {
+            f(chapter)?;
+            for_each_chapter_mut(&mut chapter.sub_items, f)?;
+        }
+        Ok(())
+    })
+}
+
+/// The source path of a chapter for messages, or its name for draft chapters.
+pub fn get_chapter_name(chapter: &Chapter) -> String {
+    match &chapter.path {
+        Some(path) => path.display().to_string(),
+        None => chapter.name.clone(),
+    }
+}
+
+/// Prints the `lines` collected for a chapter as a single warning about `what`. Chapters are
+/// processed in parallel, one `eprint!` keeps the lines of a chapter together.
+pub fn warn_chapter(chapter: &Chapter, what: &str, lines: &str) {
+    if !lines.is_empty() {
+        eprint!(
+            "Warning: {} in {}:\n{}",
+            what,
+            get_chapter_name(chapter),
+            lines
+        );
+    }
+}
+
+/// Fills the generated `page` into the chapter at `path`: at `marker` if the chapter has one,
+/// otherwise it is appended. Adds the chapter with `title` if the book has none at `path`.
+pub fn upsert_generated_chapter(
+    book: &mut Book,
+    path: &Path,
+    title: &str,
+    marker: &str,
+    page: String,
+) {
+    let mut found = false;
+    book.for_each_mut(|item: &mut BookItem| {
+        if let BookItem::Chapter(ref mut chapter) = *item {
+            if chapter.source_path.as_deref() == Some(path) {
+                found = true;
+                chapter.content = if chapter.content.contains(marker) {
+                    chapter.content.replacen(marker, &page, 1)
+                } else if chapter.content.trim().is_empty() {
+                    page.clone()
+                } else {
+                    format!("{}\n\n{}", chapter.content, page)
+                };
+            }
+        }
+    });
+    if !found {
+        book.push_item(Chapter::new(title, page, path, Vec::new()));
+    }
+}
+
 pub fn find_bin_dir(current_dir: &PathBuf, search: &PathBuf) -> Option<PathBuf> {
     let paths = fs::read_dir(current_dir);
     if paths.is_err() {